@@ -1,225 +1,334 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
 use serde_json::{Map, Value};
 
-#[derive(Debug, Clone)]
-pub struct XmlNode {
-    tag: String,
-    attributes: HashMap<String, String>,
-    children: Vec<XmlNode>,
-    text: Option<String>,
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    // The input ended while `expected` was still required; feeding more
+    // input may let the parse succeed
+    UnexpectedEof { expected: &'static str },
+    Syntax(String),
 }
 
-impl XmlNode {
-    fn new(tag: String) -> Self {
-        XmlNode {
-            tag,
-            attributes: HashMap::new(),
-            children: Vec::new(),
-            text: None,
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedEof { expected } => {
+                write!(f, "Unexpected end of input, expected {}", expected)
+            }
+            ParseError::Syntax(message) => f.write_str(message),
         }
     }
+}
 
-    pub fn to_json(&self) -> Value {
-        let mut map = Map::new();
+impl std::error::Error for ParseError {}
 
-        // Handle attributes
-        if !self.attributes.is_empty() {
-            let mut attrs = Map::new();
-            for (key, value) in self.attributes.iter() {
-                attrs.insert(key.clone(), Value::String(value.clone()));
-            }
-            map.insert("@attributes".to_string(), Value::Object(attrs));
-        }
+// Something dubious that the parser accepted, reported by `parse_with_warnings`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseWarning {
+    pub message: String,
+    // Character offset, and the 1-based line and column it falls on
+    pub position: usize,
+    pub line: usize,
+    pub column: usize,
+}
 
-        // Handle text
-        if let Some(text) = &self.text {
-            if self.children.is_empty() && self.attributes.is_empty() {
-                return Value::String(text.clone());
-            } else {
-                map.insert("#text".to_string(), Value::String(text.clone()));
-            }
-        }
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
 
-        // Handle children
-        let mut children_map: HashMap<String, Vec<Value>> = HashMap::new();
-        for child in &self.children {
-            children_map
-                .entry(child.tag.clone())
-                .or_insert_with(Vec::new)
-                .push(child.to_json());
+impl From<String> for ParseError {
+    fn from(message: String) -> Self {
+        ParseError::Syntax(message)
+    }
+}
+
+impl From<&str> for ParseError {
+    fn from(message: &str) -> Self {
+        ParseError::Syntax(message.to_string())
+    }
+}
+
+// A point in the input: 1-based line and column, and the character offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+}
+
+// Why `XmlParser` rejected its input. Every variant but `UnexpectedEof`
+// carries where parsing stopped, which `Display` appends to the message;
+// `Syntax` covers the rarer malformations that have no variant of their own.
+#[derive(Debug, Clone, PartialEq)]
+pub enum XmlError {
+    // The input ended while `expected` was still required, inside the
+    // construct `inside` if one was left open; feeding more input may let
+    // the parse succeed
+    UnexpectedEof { expected: &'static str, inside: Option<XmlConstruct> },
+    UnexpectedChar { expected: String, found: char, position: Position },
+    // `</found>` closing an element opened as `<expected>`
+    MismatchedTag { expected: String, found: String, position: Position },
+    // A character outside XML 1.0's `Char`, under `reject_invalid_chars`
+    InvalidChar { found: char, position: Position },
+    // `&name;` that is neither predefined nor declared in the DOCTYPE
+    UnknownEntity { name: String, position: Position },
+    // A `SYSTEM` or `PUBLIC` identifier, which is never fetched
+    ExternalEntity { position: Position },
+    // One of the per-element limits in `XmlParserOptions`
+    LimitExceeded { limit: XmlLimit, max: usize, position: Position },
+    Syntax { message: String, position: Position },
+}
+
+pub type XmlResult<T> = Result<T, XmlError>;
+
+// What an `XmlError::UnexpectedEof` was in the middle of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmlConstruct {
+    // A quoted attribute, declaration or entity value
+    String,
+    Cdata,
+    EntityReference,
+    Comment,
+    ProcessingInstruction,
+}
+
+impl fmt::Display for XmlConstruct {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            XmlConstruct::String => "string",
+            XmlConstruct::Cdata => "CDATA section",
+            XmlConstruct::EntityReference => "entity reference",
+            XmlConstruct::Comment => "comment",
+            XmlConstruct::ProcessingInstruction => "processing instruction",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmlLimit {
+    Attributes,
+    AttributeNameLength,
+    AttributeValueLength,
+}
+
+// Placeholder for an error built where the location isn't known, until
+// `XmlParser::locate` fills it in
+const UNLOCATED: Position = Position { line: 0, column: 0, offset: 0 };
+
+impl XmlError {
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            XmlError::UnexpectedEof { .. } => None,
+            XmlError::UnexpectedChar { position, .. }
+            | XmlError::MismatchedTag { position, .. }
+            | XmlError::InvalidChar { position, .. }
+            | XmlError::UnknownEntity { position, .. }
+            | XmlError::ExternalEntity { position }
+            | XmlError::LimitExceeded { position, .. }
+            | XmlError::Syntax { position, .. } => Some(*position),
         }
+    }
 
-        for (tag, values) in children_map {
-            let json_val = if values.len() == 1 {
-                values.into_iter().next().unwrap()
-            } else {
-                Value::Array(values)
-            };
-            map.insert(tag, json_val);
+    fn position_mut(&mut self) -> Option<&mut Position> {
+        match self {
+            XmlError::UnexpectedEof { .. } => None,
+            XmlError::UnexpectedChar { position, .. }
+            | XmlError::MismatchedTag { position, .. }
+            | XmlError::InvalidChar { position, .. }
+            | XmlError::UnknownEntity { position, .. }
+            | XmlError::ExternalEntity { position }
+            | XmlError::LimitExceeded { position, .. }
+            | XmlError::Syntax { position, .. } => Some(position),
         }
+    }
+}
 
-        if map.is_empty() {
-            return Value::Null;
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XmlError::UnexpectedEof { inside: Some(construct), .. } => {
+                return write!(f, "Unterminated {}", construct);
+            }
+            XmlError::UnexpectedEof { expected, inside: None } => {
+                return write!(f, "Unexpected end of input, expected {}", expected);
+            }
+            XmlError::UnexpectedChar { expected, found, .. } => {
+                write!(f, "Expected {}, found '{}'", expected, found)?
+            }
+            XmlError::MismatchedTag { expected, found, .. } => {
+                write!(f, "Mismatched tags: {} and {}", expected, found)?
+            }
+            XmlError::InvalidChar { found, .. } => write!(f, "Invalid character U+{:04X}", *found as u32)?,
+            XmlError::UnknownEntity { name, .. } => write!(f, "Unknown entity reference '&{};'", name)?,
+            XmlError::ExternalEntity { .. } => f.write_str("External entities are not supported")?,
+            XmlError::LimitExceeded { limit, max, .. } => match limit {
+                XmlLimit::Attributes => write!(f, "Element has more than the maximum of {} attributes", max)?,
+                XmlLimit::AttributeNameLength => write!(f, "Attribute name exceeds the maximum length of {}", max)?,
+                XmlLimit::AttributeValueLength => write!(f, "Attribute value exceeds the maximum length of {}", max)?,
+            },
+            XmlError::Syntax { message, .. } => f.write_str(message)?,
+        }
+        match self.position() {
+            Some(Position { line, column, .. }) if line > 0 => {
+                write!(f, " at line {}, column {}", line, column)
+            }
+            _ => Ok(()),
         }
+    }
+}
+
+impl std::error::Error for XmlError {}
 
-        Value::Object(map)
+impl From<String> for XmlError {
+    fn from(message: String) -> Self {
+        XmlError::Syntax { message, position: UNLOCATED }
     }
 }
 
-pub struct XmlParser {
+impl From<&str> for XmlError {
+    fn from(message: &str) -> Self {
+        XmlError::Syntax { message: message.to_string(), position: UNLOCATED }
+    }
+}
+
+struct LineStarts(Vec<usize>);
+
+impl LineStarts {
+    fn line_column(&self, offset: usize) -> (usize, usize) {
+        let line = self.0.partition_point(|&start| start <= offset);
+        (line, offset - self.0[line - 1] + 1)
+    }
+}
+
+// Warnings are recorded without a line and column, which are filled in here
+// once the parse is over
+fn locate_warnings(warnings: &mut [ParseWarning], scanner: &Scanner) {
+    let line_starts = scanner.line_starts();
+    for warning in warnings {
+        (warning.line, warning.column) = line_starts.line_column(warning.position);
+    }
+}
+
+// Character cursor shared by the parsers, usable for building other parsers
+pub struct Scanner {
     input: Vec<char>,
     position: usize,
 }
 
-impl XmlParser {
+impl Scanner {
     pub fn new(input: &str) -> Self {
-        XmlParser {
+        Scanner {
             input: input.chars().collect(),
             position: 0,
         }
     }
 
-    pub fn parse(&mut self) -> Result<XmlNode, String> {
-        self.skip_whitespace();
-        self.expect_char('<')?;
-        
-        let tag = self.parse_tag_name()?;
-        let mut node = XmlNode::new(tag);
-        
-        // Parse attributes
-        node.attributes = self.parse_attributes()?;
-        
-        // Check if it's a self-closing tag
-        self.skip_whitespace();
-        if self.peek_char() == Some('/') {
-            self.next_char();
-            self.expect_char('>')?;
-            return Ok(node);
-        }
-        
-        self.expect_char('>')?;
-        
-        // Parse content (text and child nodes)
-        loop {
-            self.skip_whitespace();
-            
-            if self.peek_char() == Some('<') {
-                if self.peek_next_char() == Some('/') {
-                    self.next_char(); // Skip '<'
-                    self.next_char(); // Skip '/'
-                    let close_tag = self.parse_tag_name()?;
-                    
-                    if close_tag != node.tag {
-                        return Err(format!("Mismatched tags: {} and {}", node.tag, close_tag));
-                    }
-                    
-                    self.expect_char('>')?;
-                    break;
-                } else {
-                    let child = self.parse()?;
-                    node.children.push(child);
-                }
-            } else {
-                let text = self.parse_text()?;
-                if !text.trim().is_empty() {
-                    node.text = Some(text);
-                }
-            }
-        }
-        
-        Ok(node)
+    pub fn from_chars(input: Vec<char>) -> Self {
+        Scanner { input, position: 0 }
     }
-    
-    fn parse_tag_name(&mut self) -> Result<String, String> {
-        let mut name = String::new();
-        
-        while let Some(c) = self.peek_char() {
-            if c.is_alphanumeric() || c == '_' || c == '-' {
-                name.push(self.next_char().unwrap());
+
+    // Starts over on new input, reusing the buffer of the previous one
+    pub fn reset(&mut self, input: &str) {
+        self.input.clear();
+        self.input.extend(input.chars());
+        self.position = 0;
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    // 1-based line and column of the current position
+    pub fn line_column(&self) -> (usize, usize) {
+        self.line_column_at(self.position)
+    }
+
+    pub fn location(&self) -> Position {
+        let (line, column) = self.line_column();
+        Position { line, column, offset: self.position }
+    }
+
+    // Where each line starts, for locating many offsets with a single scan
+    fn line_starts(&self) -> LineStarts {
+        let mut starts = vec![0];
+        starts.extend((0..self.input.len()).filter(|&i| self.input[i] == '\n').map(|i| i + 1));
+        LineStarts(starts)
+    }
+
+    pub fn line_column_at(&self, position: usize) -> (usize, usize) {
+        let mut line = 1;
+        let mut column = 1;
+        for &c in &self.input[..position.min(self.input.len())] {
+            if c == '\n' {
+                line += 1;
+                column = 1;
             } else {
-                break;
+                column += 1;
             }
         }
-        
-        if name.is_empty() {
-            return Err("Expected tag name".to_string());
-        }
-        
-        Ok(name)
+        (line, column)
     }
-    
-    fn parse_attributes(&mut self) -> Result<HashMap<String, String>, String> {
-        let mut attributes = HashMap::new();
-        
-        loop {
-            self.skip_whitespace();
-            
-            if self.peek_char() == Some('>') || self.peek_char() == Some('/') {
-                break;
-            }
-            
-            let name = self.parse_tag_name()?;
-            self.skip_whitespace();
-            self.expect_char('=')?;
-            self.skip_whitespace();
-            self.expect_char('"')?;
-            
-            let value = self.parse_attribute_value()?;
-            attributes.insert(name, value);
-        }
-        
-        Ok(attributes)
+
+    // Moves back (or forward) to an earlier `position()`, clamped to the input
+    pub fn set_position(&mut self, position: usize) {
+        self.position = position.min(self.input.len());
     }
-    
-    fn parse_attribute_value(&mut self) -> Result<String, String> {
-        let mut value = String::new();
-        
-        while let Some(c) = self.next_char() {
-            if c == '"' {
-                return Ok(value);
-            }
-            value.push(c);
-        }
-        
-        Err("Unterminated attribute value".to_string())
+
+    // The characters between an earlier `position()` and the current one
+    pub fn consumed_since(&self, start: usize) -> &[char] {
+        &self.input[start.min(self.position)..self.position]
     }
-    
-    fn parse_text(&mut self) -> Result<String, String> {
-        let mut text = String::new();
-        
-        while let Some(c) = self.peek_char() {
-            if c == '<' {
-                break;
-            }
-            text.push(self.next_char().unwrap());
-        }
-        
-        Ok(text)
+
+    pub fn is_at_end(&self) -> bool {
+        self.position >= self.input.len()
     }
-    
-    fn peek_char(&self) -> Option<char> {
+
+    pub fn remaining(&self) -> &[char] {
+        &self.input[self.position..]
+    }
+
+    pub fn peek_char(&self) -> Option<char> {
         self.input.get(self.position).copied()
     }
 
-    fn peek_next_char(&self) -> Option<char> {
+    pub fn peek_next_char(&self) -> Option<char> {
         self.input.get(self.position + 1).copied()
     }
 
-    fn next_char(&mut self) -> Option<char> {
+    pub fn next_char(&mut self) -> Option<char> {
         let c = self.peek_char();
-        self.position += 1;
+        if c.is_some() {
+            self.position += 1;
+        }
         c
     }
 
-    fn expect_char(&mut self, expected: char) -> Result<(), String> {
-        match self.next_char() {
-            Some(c) if c == expected => Ok(()),
-            Some(c) => Err(format!("Expected '{}', found '{}'", expected, c)),
-            None => Err(format!("Expected '{}', found end of input", expected)),
+    pub fn starts_with(&self, literal: &str) -> bool {
+        let rest = self.remaining();
+        literal.chars().enumerate().all(|(i, c)| rest.get(i) == Some(&c))
+    }
+
+    // Whether the input ends partway through `literal`
+    pub fn ends_inside(&self, literal: &str) -> bool {
+        let rest = self.remaining();
+        !rest.is_empty() && rest.len() < literal.chars().count() && literal.chars().zip(rest).all(|(c, &r)| c == r)
+    }
+
+    // Advances past `literal` if the input continues with it
+    pub fn consume_literal(&mut self, literal: &str) -> bool {
+        if self.starts_with(literal) {
+            self.position += literal.chars().count();
+            true
+        } else {
+            false
         }
     }
-    
-    fn skip_whitespace(&mut self) {
+
+    pub fn skip_whitespace(&mut self) {
         while let Some(c) = self.peek_char() {
             if !c.is_whitespace() {
                 break;
@@ -229,172 +338,3728 @@ impl XmlParser {
     }
 }
 
-pub enum JsonValue {
-    Null,
-    Boolean(bool),
-    Number(f64),
-    String(String),
-    Array(Vec<JsonValue>),
-    Object(HashMap<String, JsonValue>),
+// How `XmlNode::to_xml_string` writes an element with no text or children
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EmptyElementStyle {
+    // `<a/>`
+    #[default]
+    SelfClosing,
+    // `<a></a>`, for consumers that don't accept self-closing tags
+    ExplicitClose,
 }
 
-impl JsonValue {
-    pub fn to_xml(&self) -> String {
-        self.to_xml_with_tag("root")
-    }
-
-    fn to_xml_with_tag(&self, tag: &str) -> String {
-        match self {
-            JsonValue::Null => format!("<{}>", tag),
-            JsonValue::Boolean(b) => format!("<{}>{}</{}>", tag, b, tag),
-            JsonValue::Number(n) => format!("<{}>{}</{}>", tag, n, tag),
-            JsonValue::String(s) => format!("<{}>{}</{}>", tag, escape_xml_text(s), tag),
-            JsonValue::Array(arr) => {
-                let mut xml = String::new();
-                xml.push_str(&format!("<{}>", tag));
-                for value in arr.iter() {
-                    xml.push_str("  ");
-                    xml.push_str(&value.to_xml_with_tag("item"));
-                }
-                xml.push_str(&format!("</{}>", tag));
-                xml
-            }
-            JsonValue::Object(obj) => {
-                let mut xml = String::new();
-                xml.push_str(&format!("<{}>", tag));
-                for (key, value) in obj {
-                    xml.push_str("  ");
-                    xml.push_str(&value.to_xml_with_tag(key));
-                }
-                xml.push_str(&format!("</{}>", tag));
-                xml
-            }
-        }
-    }
+#[derive(Debug, Clone, Default)]
+pub struct WriteXmlOptions {
+    pub empty_element_style: EmptyElementStyle,
+    // End the output with '\n', as many tools expect of a file. Off by
+    // default; `pretty()`, which `to_xml_pretty` uses, turns it on.
+    pub trailing_newline: bool,
 }
 
-fn escape_xml_text(text: &str) -> String {
-    text.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-        .replace('"', "&quot;")
-        .replace('\'', "&apos;")
+impl WriteXmlOptions {
+    // The defaults for `to_xml_pretty` and `reformat_xml`
+    pub fn pretty() -> Self {
+        WriteXmlOptions { trailing_newline: true, ..Default::default() }
+    }
 }
 
-pub struct JsonParser {
-    input: Vec<char>,
-    position: usize,
+#[derive(Debug, Clone, PartialEq)]
+pub struct XmlNode {
+    // Shared so a parser can intern repeated names, see `XmlParserOptions::intern_names`
+    tag: Arc<str>,
+    attributes: HashMap<Arc<str>, String>,
+    children: Vec<XmlNode>,
+    text: Option<String>,
 }
 
-impl JsonParser {
-    pub fn new(input: &str) -> Self {
-        JsonParser {
-            input: input.chars().collect(),
-            position: 0,
+impl XmlNode {
+    pub fn new(tag: String) -> Self {
+        XmlNode {
+            tag: tag.into(),
+            attributes: HashMap::new(),
+            children: Vec::new(),
+            text: None,
         }
     }
 
-    pub fn parse(&mut self) -> Result<JsonValue, String> {
-        let value = self.parse_value()?;
-        self.skip_whitespace();
-        if self.position < self.input.len() {
-            return Err("Unexpected characters after JSON value".to_string());
-        }
-        Ok(value)
+    pub fn tag(&self) -> &str {
+        &self.tag
     }
 
-    fn parse_null(&mut self) -> Result<JsonValue, String> {
-        if self.input[self.position..].starts_with(&['n', 'u', 'l', 'l']) {
-            self.position += 4;
-            Ok(JsonValue::Null)
-        } else {
-            Err("Expected null".to_string())
-        }
+    pub fn attributes(&self) -> &HashMap<Arc<str>, String> {
+        &self.attributes
     }
 
-    fn parse_boolean(&mut self) -> Result<JsonValue, String> {
-        if self.input[self.position..].starts_with(&['t', 'r', 'u', 'e']) {
-            self.position += 4;
-            Ok(JsonValue::Boolean(true))
-        } else if self.input[self.position..].starts_with(&['f', 'a', 'l', 's', 'e']) {
-            self.position += 5;
-            Ok(JsonValue::Boolean(false))
-        } else {
-            Err("Expected true or false".to_string())
-        }
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(String::as_str)
     }
 
-    fn parse_string(&mut self) -> Result<JsonValue, String> {
-        self.next_char(); // Skip opening quote
-        let mut string = String::new();
-        
-        while let Some(c) = self.next_char() {
-            match c {
-                '"' => return Ok(JsonValue::String(string)),
-                '\\' => {
-                    if let Some(next) = self.next_char() {
-                        match next {
-                            '"' | '\\' | '/' => string.push(next),
-                            'b' => string.push('\x08'),
-                            'f' => string.push('\x0c'),
-                            'n' => string.push('\n'),
-                            'r' => string.push('\r'),
-                            't' => string.push('\t'),
-                            _ => return Err("Invalid escape sequence".to_string()),
-                        }
-                    }
-                }
-                _ => string.push(c),
-            }
-        }
-        
-        Err("Unterminated string".to_string())
+    // (name, value) pairs ordered by name
+    pub fn attributes_sorted(&self) -> Vec<(&str, &str)> {
+        let mut attributes: Vec<_> = self
+            .attributes
+            .iter()
+            .map(|(name, value)| (&**name, value.as_str()))
+            .collect();
+        attributes.sort_unstable();
+        attributes
     }
 
-    fn parse_number(&mut self) -> Result<JsonValue, String> {
-        let mut number = String::new();
-        
-        if self.peek_char() == Some('-') {
-            number.push(self.next_char().unwrap());
+    // A C14N-like form for hashing and signing: attributes sorted by name and
+    // double-quoted, empty elements written as `<a></a>`, each element's text
+    // ahead of its children, and the escaping rules of Canonical XML 1.0.
+    // Comments, namespaces and DTDs are not handled.
+    pub fn to_canonical_xml(&self) -> String {
+        let mut xml = String::new();
+        self.write_canonical(&mut xml);
+        xml
+    }
+
+    fn write_canonical(&self, xml: &mut String) {
+        xml.push('<');
+        xml.push_str(&self.tag);
+        for (name, value) in self.attributes_sorted() {
+            xml.push(' ');
+            xml.push_str(name);
+            xml.push_str("=\"");
+            push_canonical_escaped(xml, value, true);
+            xml.push('"');
         }
-        
-        while let Some(c) = self.peek_char() {
-            if c.is_digit(10) {
-                number.push(self.next_char().unwrap());
-            } else {
-                break;
-            }
+        xml.push('>');
+        if let Some(text) = &self.text {
+            push_canonical_escaped(xml, text, false);
         }
+        for child in &self.children {
+            child.write_canonical(xml);
+        }
+        xml.push_str("</");
+        xml.push_str(&self.tag);
+        xml.push('>');
+    }
 
-        if self.peek_char() == Some('.') {
-            number.push(self.next_char().unwrap());
-            let mut has_digit = false;
+    // The element as XML that `XmlParser` reads back into an equal tree:
+    // attributes sorted by name, the text ahead of the children, and
+    // `<a/>` for an element with neither
+    pub fn to_xml_string(&self) -> String {
+        self.to_xml_string_with_options(&WriteXmlOptions::default())
+    }
 
-            while let Some(c) = self.peek_char() {
-                if c.is_digit(10) {
-                    number.push(self.next_char().unwrap());
-                    has_digit = true;
-                } else {
-                    break;
+    pub fn to_xml_string_with_options(&self, options: &WriteXmlOptions) -> String {
+        let mut xml = String::new();
+        self.write_xml(&mut xml, options);
+        if options.trailing_newline {
+            xml.push('\n');
+        }
+        xml
+    }
+
+    fn write_xml(&self, xml: &mut String, options: &WriteXmlOptions) {
+        self.write_start_tag(xml);
+        if self.children.is_empty() && self.text.is_none() {
+            match options.empty_element_style {
+                EmptyElementStyle::SelfClosing => xml.push_str("/>"),
+                EmptyElementStyle::ExplicitClose => {
+                    xml.push('>');
+                    self.write_end_tag(xml);
                 }
             }
-            
-            if !has_digit {
-                return Err("Expected digit after decimal point".to_string());
-            }
+            return;
+        }
+        xml.push('>');
+        if let Some(text) = &self.text {
+            xml.push_str(&escape_xml_text(text, &EscapeOptions::default()));
         }
+        for child in &self.children {
+            child.write_xml(xml, options);
+        }
+        self.write_end_tag(xml);
+    }
 
-        if let Some('e') | Some('E') = self.peek_char() {
-            number.push(self.next_char().unwrap());
-            
-            if let Some('+') | Some('-') = self.peek_char() {
-                number.push(self.next_char().unwrap());
+    // Like `to_xml_string`, with each element on its own line and children
+    // indented by `indent` spaces per level. An element without children
+    // keeps its text on the same line; otherwise the text gets a line of its
+    // own, which only reads back the same with `XmlParserOptions::trim_text`.
+    pub fn to_xml_pretty(&self, indent: usize) -> String {
+        self.to_xml_pretty_with_options(indent, &WriteXmlOptions::pretty())
+    }
+
+    pub fn to_xml_pretty_with_options(&self, indent: usize, options: &WriteXmlOptions) -> String {
+        let mut xml = String::new();
+        self.write_xml_pretty(&mut xml, indent, 0, options);
+        if options.trailing_newline {
+            xml.push('\n');
+        }
+        xml
+    }
+
+    fn write_xml_pretty(&self, xml: &mut String, indent: usize, depth: usize, options: &WriteXmlOptions) {
+        xml.push_str(&" ".repeat(indent * depth));
+        if self.children.is_empty() {
+            self.write_xml(xml, options);
+            return;
+        }
+        self.write_start_tag(xml);
+        xml.push_str(">\n");
+        if let Some(text) = &self.text {
+            xml.push_str(&" ".repeat(indent * (depth + 1)));
+            xml.push_str(&escape_xml_text(text, &EscapeOptions::default()));
+            xml.push('\n');
+        }
+        for child in &self.children {
+            child.write_xml_pretty(xml, indent, depth + 1, options);
+            xml.push('\n');
+        }
+        xml.push_str(&" ".repeat(indent * depth));
+        self.write_end_tag(xml);
+    }
+
+    // `<tag name="value" ...`, left open for '>' or "/>". Values are escaped
+    // as in `to_canonical_xml`, so tabs and line breaks survive the
+    // normalization a conforming parser applies to attribute values.
+    fn write_start_tag(&self, xml: &mut String) {
+        xml.push('<');
+        xml.push_str(&self.tag);
+        for (name, value) in self.attributes_sorted() {
+            xml.push(' ');
+            xml.push_str(name);
+            xml.push_str("=\"");
+            push_canonical_escaped(xml, value, true);
+            xml.push('"');
+        }
+    }
+
+    fn write_end_tag(&self, xml: &mut String) {
+        xml.push_str("</");
+        xml.push_str(&self.tag);
+        xml.push('>');
+    }
+
+    pub fn children(&self) -> &[XmlNode] {
+        &self.children
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    pub fn find_child_ci(&self, tag: &str) -> Option<&XmlNode> {
+        self.children
+            .iter()
+            .find(|child| child.tag.to_lowercase() == tag.to_lowercase())
+    }
+
+    // Direct children whose tag matches ignoring case
+    pub fn find_children_ci<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a XmlNode> + 'a {
+        self.children
+            .iter()
+            .filter(move |child| child.tag.to_lowercase() == tag.to_lowercase())
+    }
+
+    pub fn find_child_local(&self, local_name: &str) -> Option<&XmlNode> {
+        self.children
+            .iter()
+            .find(|child| local_name_of(&child.tag) == local_name_of(local_name))
+    }
+
+    // Direct children whose tag matches after dropping any namespace prefix,
+    // so "Body" finds both <Body> and <soap:Body>
+    pub fn find_children_local<'a>(&'a self, local_name: &'a str) -> impl Iterator<Item = &'a XmlNode> + 'a {
+        self.children
+            .iter()
+            .filter(move |child| local_name_of(&child.tag) == local_name_of(local_name))
+    }
+
+    // First descendant along a slash-separated path of tags, e.g. "book/title".
+    // A leading '/' also matches this node's own tag: "/catalog/book/title".
+    pub fn find(&self, path: &str) -> Option<&XmlNode> {
+        let mut current = self;
+        let mut steps = path.split('/');
+        if let Some(relative) = path.strip_prefix('/') {
+            steps = relative.split('/');
+            if steps.next()? != &*self.tag {
+                return None;
+            }
+        }
+        for step in steps.filter(|step| !step.is_empty()) {
+            current = current.children.iter().find(|child| &*child.tag == step)?;
+        }
+        Some(current)
+    }
+
+    // This node's text followed by that of its descendants, in document order
+    pub fn text_content(&self) -> String {
+        let mut content = String::new();
+        self.collect_text(&mut content);
+        content
+    }
+
+    fn collect_text(&self, content: &mut String) {
+        if let Some(text) = &self.text {
+            content.push_str(text);
+        }
+        for child in &self.children {
+            child.collect_text(content);
+        }
+    }
+
+    pub fn query_text(&self, path: &str) -> Option<String> {
+        self.find(path).map(XmlNode::text_content)
+    }
+
+    // Every node in document order with its absolute path, in the form `find`
+    // accepts ("/catalog/book/title"), and its depth below this node (0 for
+    // this node). Repeated siblings share a path.
+    pub fn traverse(&self) -> Vec<(String, usize, &XmlNode)> {
+        let mut nodes = Vec::new();
+        self.collect_nodes(String::new(), 0, &mut nodes);
+        nodes
+    }
+
+    fn collect_nodes<'a>(&'a self, prefix: String, depth: usize, nodes: &mut Vec<(String, usize, &'a XmlNode)>) {
+        let path = format!("{}/{}", prefix, self.tag);
+        nodes.push((path.clone(), depth, self));
+        for child in &self.children {
+            child.collect_nodes(path.clone(), depth + 1, nodes);
+        }
+    }
+
+    pub fn add_child(&mut self, child: XmlNode) {
+        self.children.push(child);
+    }
+
+    pub fn remove_child(&mut self, index: usize) -> Option<XmlNode> {
+        if index < self.children.len() {
+            Some(self.children.remove(index))
+        } else {
+            None
+        }
+    }
+
+    // Removes every direct child with the given tag, returning them in document order
+    pub fn remove_children_by_tag(&mut self, tag: &str) -> Vec<XmlNode> {
+        let (removed, kept) = std::mem::take(&mut self.children)
+            .into_iter()
+            .partition(|child| &*child.tag == tag);
+        self.children = kept;
+        removed
+    }
+
+    pub fn set_attribute(&mut self, name: &str, value: &str) -> Option<String> {
+        self.attributes.insert(name.into(), value.to_string())
+    }
+
+    pub fn remove_attribute(&mut self, name: &str) -> Option<String> {
+        self.attributes.remove(name)
+    }
+
+    pub fn set_text(&mut self, text: Option<String>) {
+        self.text = text;
+    }
+
+    pub fn to_json(&self) -> Value {
+        self.to_json_with_options(&ToJsonOptions::default())
+    }
+
+    pub fn to_json_with_options(&self, options: &ToJsonOptions) -> Value {
+        self.to_json_value_with_options(options).to_serde()
+    }
+
+    pub fn to_json_value(&self) -> JsonValue {
+        self.to_json_value_with_options(&ToJsonOptions::default())
+    }
+
+    pub fn to_json_value_with_options(&self, options: &ToJsonOptions) -> JsonValue {
+        self.to_ordered_json(options).into_json_value()
+    }
+
+    // Compact JSON text with object members in document order: attributes
+    // (sorted by name), then text, then children in order of first
+    // appearance. `to_json` can't do this, as serde's map sorts its keys.
+    pub fn to_json_string(&self) -> String {
+        self.to_json_string_with_options(&ToJsonOptions::default())
+    }
+
+    pub fn to_json_string_with_options(&self, options: &ToJsonOptions) -> String {
+        let mut json = String::new();
+        self.to_ordered_json(options)
+            .write_json(&mut json)
+            .expect("writing to a String cannot fail");
+        json
+    }
+
+    fn to_ordered_json(&self, options: &ToJsonOptions) -> OrderedJson {
+        let scope = self.namespace_scope(&[], options);
+        self.to_json_at(&format!("/{}", self.tag), &scope, options)
+    }
+
+    // `path` is this element's absolute path, e.g. "/catalog/book", and `scope`
+    // the (prefix, uri) namespace declarations visible on it, innermost last
+    fn to_json_at(&self, path: &str, scope: &[(String, String)], options: &ToJsonOptions) -> OrderedJson {
+        let type_hint = if options.type_hints {
+            self.attributes.get("type").map(String::as_str)
+        } else {
+            None
+        };
+
+        if let Some(value) = self.typed_json_value(type_hint, path, scope, options) {
+            return value;
+        }
+
+        let mut map = Vec::new();
+
+        // Handle attributes
+        let has_attributes = self.attributes.len() > usize::from(type_hint.is_some());
+        if has_attributes {
+            let mut attrs = Vec::new();
+            for (key, value) in self.attributes_sorted() {
+                if type_hint.is_some() && key == "type" {
+                    continue;
+                }
+                let name = if is_namespace_declaration(key) {
+                    key.to_string()
+                } else {
+                    options.namespace_keys.key_for(key, scope, false)
+                };
+                insert_member(&mut attrs, name, OrderedJson::Leaf(JsonValue::String(value.to_string())));
+            }
+            map.push(("@attributes".to_string(), OrderedJson::Object(attrs)));
+        }
+
+        // Handle text
+        if let Some(text) = &self.text {
+            let text = if options.normalize_text {
+                text.split_whitespace().collect::<Vec<_>>().join(" ")
+            } else {
+                text.clone()
+            };
+            if self.children.is_empty() && !has_attributes {
+                return OrderedJson::Leaf(JsonValue::String(text));
+            } else {
+                map.push(("#text".to_string(), OrderedJson::Leaf(JsonValue::String(text))));
+            }
+        }
+
+        // Handle children, grouped in first-appearance order so the conversion
+        // doesn't depend on hash iteration order
+        let mut groups: Vec<(String, &str, Vec<&XmlNode>, Vec<OrderedJson>)> = Vec::new();
+        for child in &self.children {
+            let child_path = format!("{}/{}", path, child.tag);
+            let child_scope = child.namespace_scope(scope, options);
+            let key = options.namespace_keys.key_for(&child.tag, &child_scope, true);
+            let value = child.to_json_at(&child_path, &child_scope, options);
+            match groups.iter_mut().find(|(existing, _, _, _)| *existing == key) {
+                Some((_, _, nodes, values)) => {
+                    nodes.push(child);
+                    values.push(value);
+                }
+                None => groups.push((key, &child.tag, vec![child], vec![value])),
+            }
+        }
+
+        let sibling_keys: HashSet<String> = match options.duplicate_keys {
+            DuplicateKeyStrategy::Suffix => groups.iter().map(|(key, _, _, _)| key.clone()).collect(),
+            DuplicateKeyStrategy::Array => HashSet::new(),
+        };
+        for (key, tag, nodes, values) in groups {
+            let forced = options.force_array_paths.contains(&format!("{}/{}", path, tag));
+            let json_val = if values.len() == 1 && !forced {
+                values.into_iter().next().unwrap()
+            } else if let Some(merged) = merge_by_attribute(&nodes, &values, options).filter(|_| !forced) {
+                merged
+            } else if options.duplicate_keys == DuplicateKeyStrategy::Suffix && !forced {
+                let mut suffixed = (2..)
+                    .map(|number| format!("{}_{}", key, number))
+                    .filter(|name| !sibling_keys.contains(name));
+                for (i, value) in values.into_iter().enumerate() {
+                    let name = if i == 0 { key.clone() } else { suffixed.next().unwrap() };
+                    insert_member(&mut map, name, value);
+                }
+                continue;
+            } else {
+                OrderedJson::Array(values)
+            };
+            insert_member(&mut map, key, json_val);
+        }
+
+        if map.is_empty() && type_hint != Some("object") {
+            return OrderedJson::Leaf(JsonValue::Null);
+        }
+
+        OrderedJson::Object(map)
+    }
+
+    // Rebuilds scalars and arrays from a `type="..."` hint written by
+    // `ToXmlOptions::type_hints`. Objects and unknown hints return None and
+    // are converted as usual.
+    fn typed_json_value(
+        &self,
+        type_hint: Option<&str>,
+        path: &str,
+        scope: &[(String, String)],
+        options: &ToJsonOptions,
+    ) -> Option<OrderedJson> {
+        let text = self.text.as_deref().unwrap_or("");
+        let value = match type_hint? {
+            "null" => JsonValue::Null,
+            "boolean" => JsonValue::Boolean(text.trim() == "true"),
+            "number" => {
+                let text = text.trim();
+                match text.parse() {
+                    Ok(n) => JsonValue::Integer(n),
+                    Err(_) => JsonValue::Number(text.parse().ok()?),
+                }
+            }
+            "string" => JsonValue::String(text.to_string()),
+            "array" => return Some(OrderedJson::Array(
+                self.children
+                    .iter()
+                    .map(|child| {
+                        let child_scope = child.namespace_scope(scope, options);
+                        child.to_json_at(&format!("{}/{}", path, child.tag), &child_scope, options)
+                    })
+                    .collect(),
+            )),
+            _ => return None,
+        };
+        Some(OrderedJson::Leaf(value))
+    }
+
+    // Adds this element's xmlns declarations to the parent's scope. Only URI
+    // keys need the scope, so the other policies skip the work.
+    fn namespace_scope(&self, parent: &[(String, String)], options: &ToJsonOptions) -> Vec<(String, String)> {
+        let mut scope = parent.to_vec();
+        if options.namespace_keys == NamespaceKeys::Uri {
+            for (name, uri) in &self.attributes {
+                if &**name == "xmlns" {
+                    scope.push((String::new(), uri.clone()));
+                } else if let Some(prefix) = name.strip_prefix("xmlns:") {
+                    scope.push((prefix.to_string(), uri.clone()));
+                }
+            }
+        }
+        scope
+    }
+}
+
+// The `ToJsonOptions::merge_by_attribute` object for a group of repeated
+// children, or None if any child lacks the attribute or two share a value
+fn merge_by_attribute(nodes: &[&XmlNode], values: &[OrderedJson], options: &ToJsonOptions) -> Option<OrderedJson> {
+    let attribute = options.merge_by_attribute.as_deref()?;
+    let mut merged: Vec<(String, OrderedJson)> = Vec::new();
+    for (node, value) in nodes.iter().zip(values) {
+        let id = node.attribute(attribute)?;
+        if merged.iter().any(|(existing, _)| existing == id) {
+            return None;
+        }
+        merged.push((id.to_string(), value.clone()));
+    }
+    Some(OrderedJson::Object(merged))
+}
+
+// The JSON form of an element before its objects become `HashMap`s, with
+// members kept in the order `to_json_at` produced them
+#[derive(Debug, Clone)]
+enum OrderedJson {
+    Leaf(JsonValue),
+    Array(Vec<OrderedJson>),
+    Object(Vec<(String, OrderedJson)>),
+}
+
+impl OrderedJson {
+    fn into_json_value(self) -> JsonValue {
+        match self {
+            OrderedJson::Leaf(value) => value,
+            OrderedJson::Array(items) => JsonValue::Array(items.into_iter().map(OrderedJson::into_json_value).collect()),
+            OrderedJson::Object(members) => JsonValue::Object(
+                members.into_iter().map(|(key, value)| (key, value.into_json_value())).collect(),
+            ),
+        }
+    }
+
+    fn write_json<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        match self {
+            OrderedJson::Leaf(value) => value.write_compact(w),
+            OrderedJson::Array(items) => {
+                w.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        w.write_char(',')?;
+                    }
+                    item.write_json(w)?;
+                }
+                w.write_char(']')
+            }
+            OrderedJson::Object(members) => {
+                w.write_char('{')?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        w.write_char(',')?;
+                    }
+                    write_json_string(w, key)?;
+                    w.write_char(':')?;
+                    value.write_json(w)?;
+                }
+                w.write_char('}')
+            }
+        }
+    }
+}
+
+// A later member with the same key replaces the earlier one in its place,
+// as inserting into a map would
+fn insert_member(members: &mut Vec<(String, OrderedJson)>, key: String, value: OrderedJson) {
+    match members.iter_mut().find(|(existing, _)| *existing == key) {
+        Some((_, existing)) => *existing = value,
+        None => members.push((key, value)),
+    }
+}
+
+fn push_canonical_escaped(xml: &mut String, text: &str, in_attribute: bool) {
+    for c in text.chars() {
+        match c {
+            '&' => xml.push_str("&amp;"),
+            '<' => xml.push_str("&lt;"),
+            '>' if !in_attribute => xml.push_str("&gt;"),
+            '"' if in_attribute => xml.push_str("&quot;"),
+            '\t' if in_attribute => xml.push_str("&#x9;"),
+            '\n' if in_attribute => xml.push_str("&#xA;"),
+            '\r' => xml.push_str("&#xD;"),
+            _ => xml.push(c),
+        }
+    }
+}
+
+fn local_name_of(name: &str) -> &str {
+    name.split_once(':').map_or(name, |(_, local)| local)
+}
+
+fn is_namespace_declaration(name: &str) -> bool {
+    name == "xmlns" || name.starts_with("xmlns:")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NamespaceKeys {
+    // Keep names as written, e.g. "soap:Body"
+    #[default]
+    Prefixed,
+    // Drop the prefix, e.g. "Body"
+    LocalName,
+    // Replace the prefix with its resolved URI in Clark notation, e.g.
+    // "{http://schemas.xmlsoap.org/soap/envelope/}Body"
+    Uri,
+}
+
+impl NamespaceKeys {
+    // Unprefixed elements take the default namespace; unprefixed attributes
+    // are never in a namespace
+    fn key_for(&self, name: &str, scope: &[(String, String)], is_element: bool) -> String {
+        let (prefix, local) = match name.split_once(':') {
+            Some((prefix, local)) => (Some(prefix), local),
+            None => (None, name),
+        };
+
+        match self {
+            NamespaceKeys::Prefixed => name.to_string(),
+            NamespaceKeys::LocalName => local.to_string(),
+            NamespaceKeys::Uri => {
+                let lookup = match prefix {
+                    Some(prefix) => Some(prefix),
+                    None if is_element => Some(""),
+                    None => None,
+                };
+                let uri = lookup.and_then(|prefix| {
+                    scope.iter().rev().find(|(p, _)| p == prefix).map(|(_, uri)| uri)
+                });
+                match (prefix, uri) {
+                    (_, Some(uri)) if !uri.is_empty() => format!("{{{}}}{}", uri, local),
+                    // Undeclared prefixes are left as written
+                    (Some(_), None) => name.to_string(),
+                    _ => local.to_string(),
+                }
+            }
+        }
+    }
+}
+
+// What `to_json` does with repeated child elements
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DuplicateKeyStrategy {
+    // `{"item": [a, b, c]}`
+    #[default]
+    Array,
+    // `{"item": a, "item_2": b, "item_3": c}`, numbered in document order.
+    // Numbers whose key a sibling element already has are skipped, so
+    // `<i>1</i><i>2</i><i_2>X</i_2>` gives `{"i": "1", "i_3": "2", "i_2": "X"}`.
+    Suffix,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ToJsonOptions {
+    // Element paths such as "/catalog/book/authors/author" that always become
+    // JSON arrays, even when the element occurs only once
+    pub force_array_paths: HashSet<String>,
+    pub namespace_keys: NamespaceKeys,
+    // Read the `type="..."` hints written by `ToXmlOptions::type_hints`
+    pub type_hints: bool,
+    // Turn repeated children that all carry this attribute, with distinct values,
+    // into an object keyed by it: `<item id="a"/><item id="b"/>` gives
+    // `{"item": {"a": ..., "b": ...}}`
+    pub merge_by_attribute: Option<String>,
+    pub duplicate_keys: DuplicateKeyStrategy,
+    // Trim text and collapse internal whitespace runs to single spaces.
+    // Text restored from a `type="string"` hint is left as written.
+    pub normalize_text: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct XmlParserOptions {
+    // Lowercase tag and attribute names as they are read (for HTML-ish input)
+    pub lowercase_names: bool,
+    // Per-element limits for untrusted input
+    pub max_attributes: usize,
+    pub max_attribute_name_length: usize,
+    pub max_attribute_value_length: usize,
+    // Share one allocation between repeated tag and attribute names
+    pub intern_names: bool,
+    // Accept HTML-style `<input disabled>`, storing the attribute with an empty value
+    pub allow_valueless_attributes: bool,
+    // Elements such as "script" whose content is kept verbatim as text
+    // instead of being parsed, like <script> and <style> in HTML
+    pub raw_elements: HashSet<String>,
+    // Reject characters outside the XML 1.0 `Char` production, such as most
+    // C0 controls, in text and attribute values, written out or as `&#1;`
+    pub reject_invalid_chars: bool,
+    // Reject a raw `<` in an attribute value, which XML 1.0 forbids
+    pub reject_lt_in_attribute_values: bool,
+    // Trim leading and trailing whitespace from element text and drop text that
+    // is only whitespace; when off, leaf text is kept exactly as written
+    pub trim_text: bool,
+}
+
+impl Default for XmlParserOptions {
+    fn default() -> Self {
+        XmlParserOptions {
+            lowercase_names: false,
+            max_attributes: 1024,
+            max_attribute_name_length: 1024,
+            max_attribute_value_length: 1024 * 1024,
+            intern_names: false,
+            allow_valueless_attributes: false,
+            raw_elements: HashSet::new(),
+            reject_invalid_chars: false,
+            reject_lt_in_attribute_values: false,
+            trim_text: true,
+        }
+    }
+}
+
+// Presets to start from, in the same spirit as `JsonParserOptions::strict`
+impl XmlParserOptions {
+    // Well-formed XML 1.0 only
+    pub fn strict() -> Self {
+        XmlParserOptions {
+            reject_invalid_chars: true,
+            reject_lt_in_attribute_values: true,
+            ..Default::default()
+        }
+    }
+
+    // HTML-ish input: valueless attributes and `<script>`/`<style>` kept as raw text
+    pub fn lenient() -> Self {
+        XmlParserOptions {
+            allow_valueless_attributes: true,
+            raw_elements: ["script", "style"].iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+// Adjacent text and CDATA inside an element, with the offset it starts at
+struct TextRun {
+    start: usize,
+    text: String,
+    has_cdata: bool,
+    // Where the last CDATA section ends in `text`; trimming stops there
+    cdata_end: usize,
+}
+
+impl TextRun {
+    fn new(start: usize) -> Self {
+        TextRun { start, text: String::new(), has_cdata: false, cdata_end: 0 }
+    }
+}
+
+// `<?xml version="1.0" encoding="UTF-8" standalone="yes"?>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct XmlDeclaration {
+    pub version: String,
+    // As declared; the input is already decoded, so this is informational
+    pub encoding: Option<String>,
+    pub standalone: Option<bool>,
+}
+
+// The parser never performs I/O. Anything that would require resolving an
+// external resource (a SYSTEM or PUBLIC DTD, an external general or parameter
+// entity) fails with `XmlError::ExternalEntity` instead of being ignored, so
+// untrusted input can't trigger XXE.
+pub struct XmlParser {
+    scanner: Scanner,
+    options: XmlParserOptions,
+    // Set by `validate` so the grammar is checked without keeping the tree
+    discard: bool,
+    names: HashSet<Arc<str>>,
+    // Collected only during `parse_with_warnings`
+    warnings: Option<Vec<ParseWarning>>,
+    // `<!ENTITY name "value">` declarations from the DOCTYPE
+    entities: HashMap<String, String>,
+    declaration: Option<XmlDeclaration>,
+}
+
+impl XmlParser {
+    pub fn new(input: &str) -> Self {
+        Self::with_options(input, XmlParserOptions::default())
+    }
+
+    pub fn with_options(input: &str, options: XmlParserOptions) -> Self {
+        Self::with_scanner(Scanner::new(input), options)
+    }
+
+    // Takes over an already collected buffer instead of re-collecting a &str
+    pub fn from_chars(input: Vec<char>) -> Self {
+        Self::with_scanner(Scanner::from_chars(input), XmlParserOptions::default())
+    }
+
+    fn with_scanner(scanner: Scanner, options: XmlParserOptions) -> Self {
+        XmlParser {
+            scanner,
+            options,
+            discard: false,
+            names: HashSet::new(),
+            warnings: None,
+            entities: HashMap::new(),
+            declaration: None,
+        }
+    }
+
+    // Readies the parser for another document, keeping its options and
+    // reusing its buffers
+    pub fn reset(&mut self, input: &str) {
+        self.scanner.reset(input);
+        self.discard = false;
+        self.names.clear();
+        self.warnings = None;
+        self.entities.clear();
+        self.declaration = None;
+    }
+
+    pub fn validate(&mut self) -> XmlResult<()> {
+        self.discard = true;
+        let result = self.parse().map(|_| ());
+        self.discard = false;
+        result
+    }
+
+    // Like `parse`, but also returns the warnings for input that was accepted
+    // with a lossy or lenient reading
+    pub fn parse_with_warnings(&mut self) -> XmlResult<(XmlNode, Vec<ParseWarning>)> {
+        self.warnings = Some(Vec::new());
+        let result = self.parse();
+        let mut warnings = self.warnings.take().unwrap_or_default();
+        locate_warnings(&mut warnings, &self.scanner);
+        result.map(|node| (node, warnings))
+    }
+
+    // Callers check `self.warnings` first so a plain parse doesn't build messages
+    fn warn(&mut self, position: usize, message: String) {
+        if let Some(warnings) = &mut self.warnings {
+            warnings.push(ParseWarning { message, position, line: 0, column: 0 });
+        }
+    }
+
+    pub fn parse(&mut self) -> XmlResult<XmlNode> {
+        let (node, _) = self.parse_one()?;
+        self.skip_misc().map_err(|err| self.locate(err))?;
+        if !self.scanner.is_at_end() {
+            return Err(self.locate("Unexpected characters after root element".into()));
+        }
+        Ok(node)
+    }
+
+    // Where the scanner stands, e.g. where parsing stopped after an error
+    pub fn location(&self) -> Position {
+        self.scanner.location()
+    }
+
+    // An error built without a location, such as a `Syntax` error from a
+    // bare message, is placed where parsing stopped
+    fn locate(&self, mut error: XmlError) -> XmlError {
+        if let Some(position) = error.position_mut().filter(|position| **position == UNLOCATED) {
+            *position = self.location();
+        }
+        error
+    }
+
+    // Character offset reached so far; after an error, where parsing stopped
+    pub fn position(&self) -> usize {
+        self.scanner.position()
+    }
+
+    // Parses one element and returns it with the number of characters consumed,
+    // leaving whatever follows it unread
+    pub fn parse_one(&mut self) -> XmlResult<(XmlNode, usize)> {
+        let start = self.scanner.position();
+        let node = self
+            .parse_prolog()
+            .and_then(|_| self.parse_element())
+            .map_err(|err| self.locate(err))?;
+        Ok((node, self.scanner.position() - start))
+    }
+
+    // The `<?xml ...?>` declaration of the last document parsed, if it had one
+    pub fn declaration(&self) -> Option<&XmlDeclaration> {
+        self.declaration.as_ref()
+    }
+
+    pub fn declared_encoding(&self) -> Option<&str> {
+        self.declaration.as_ref()?.encoding.as_deref()
+    }
+
+    fn parse_prolog(&mut self) -> XmlResult<()> {
+        self.scanner.consume_literal("\u{FEFF}");
+        self.scanner.skip_whitespace();
+        self.declaration = None;
+        if self.scanner.starts_with("<?xml") && self.scanner.remaining().get(5).is_some_and(|c| c.is_whitespace()) {
+            self.declaration = Some(self.parse_declaration()?);
+        }
+        self.skip_misc()?;
+        if self.scanner.starts_with("<!DOCTYPE") {
+            self.parse_doctype()?;
+            self.skip_misc()?;
+        }
+        Ok(())
+    }
+
+    fn parse_declaration(&mut self) -> XmlResult<XmlDeclaration> {
+        self.scanner.consume_literal("<?xml");
+        let mut declaration = XmlDeclaration { version: String::new(), encoding: None, standalone: None };
+        let mut has_version = false;
+        loop {
+            self.scanner.skip_whitespace();
+            self.check_truncated(&["?>"], "'?>'")?;
+            if self.scanner.consume_literal("?>") {
+                break;
+            }
+            // Case-sensitive whatever `lowercase_names` says
+            let name = self.parse_name()?;
+            self.scanner.skip_whitespace();
+            self.expect_char('=')?;
+            self.scanner.skip_whitespace();
+            let value = self.parse_declaration_value(&name)?;
+            match name.as_str() {
+                "version" if !has_version => {
+                    declaration.version = value;
+                    has_version = true;
+                }
+                "encoding" if has_version && declaration.encoding.is_none() => declaration.encoding = Some(value),
+                "standalone" if has_version && declaration.standalone.is_none() => {
+                    declaration.standalone = match value.as_str() {
+                        "yes" => Some(true),
+                        "no" => Some(false),
+                        _ => return Err(format!("Invalid standalone value '{}' in XML declaration", value).into()),
+                    };
+                }
+                _ => return Err(format!("Unexpected '{}' in XML declaration", name).into()),
+            }
+        }
+        if !has_version {
+            return Err("XML declaration is missing version".into());
+        }
+        Ok(declaration)
+    }
+
+    fn parse_declaration_value(&mut self, name: &str) -> XmlResult<String> {
+        let quote = match self.scanner.next_char() {
+            Some(c @ ('"' | '\'')) => c,
+            Some(c) => {
+                self.scanner.set_position(self.scanner.position() - 1);
+                return Err(self.unexpected_char(format!("quoted value for '{}'", name), c));
+            }
+            None => return Err(XmlError::UnexpectedEof { expected: "quoted value", inside: None }),
+        };
+        let mut value = String::new();
+        loop {
+            match self.scanner.next_char() {
+                Some(c) if c == quote => return Ok(value),
+                Some(c) => value.push(c),
+                None => return Err(XmlError::UnexpectedEof { expected: "closing quote", inside: Some(XmlConstruct::String) }),
+            }
+        }
+    }
+
+    // Input that stops partway through `<!--` or another markup opener is
+    // truncated rather than malformed
+    fn check_truncated(&self, literals: &[&str], expected: &'static str) -> XmlResult<()> {
+        if literals.iter().any(|literal| self.scanner.ends_inside(literal)) {
+            return Err(XmlError::UnexpectedEof { expected, inside: None });
+        }
+        Ok(())
+    }
+
+    // Whitespace, comments and processing instructions, which may surround
+    // the DOCTYPE and root element
+    fn skip_misc(&mut self) -> XmlResult<()> {
+        loop {
+            self.scanner.skip_whitespace();
+            self.check_truncated(&["<!--", "<!DOCTYPE"], "markup")?;
+            if self.scanner.starts_with("<!--") {
+                self.skip_comment()?;
+            } else if self.scanner.starts_with("<?") {
+                self.skip_processing_instruction()?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    // `<?target ...?>`; the parser has no use for any of them
+    fn skip_processing_instruction(&mut self) -> XmlResult<()> {
+        self.scanner.consume_literal("<?");
+        while !self.scanner.consume_literal("?>") {
+            if self.scanner.next_char().is_none() {
+                return Err(XmlError::UnexpectedEof {
+                    expected: "'?>'",
+                    inside: Some(XmlConstruct::ProcessingInstruction),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_comment(&mut self) -> XmlResult<()> {
+        self.scanner.consume_literal("<!--");
+        while !self.scanner.consume_literal("-->") {
+            if self.scanner.next_char().is_none() {
+                return Err(XmlError::UnexpectedEof { expected: "'-->'", inside: Some(XmlConstruct::Comment) });
+            }
+        }
+        Ok(())
+    }
+
+    // Reads a DOCTYPE, registering the internal subset's general entities.
+    // Only internal text entities are supported; anything that would need the
+    // DTD or an entity fetched from elsewhere is an error, so the parser never
+    // performs I/O (XXE).
+    fn parse_doctype(&mut self) -> XmlResult<()> {
+        self.scanner.consume_literal("<!DOCTYPE");
+        self.expect_whitespace()?;
+        self.parse_tag_name()?;
+        self.scanner.skip_whitespace();
+
+        if self.at_external_id() {
+            return Err(XmlError::ExternalEntity { position: self.location() });
+        }
+
+        if self.scanner.peek_char() == Some('[') {
+            self.scanner.next_char();
+            loop {
+                self.scanner.skip_whitespace();
+                self.check_truncated(&["<!ENTITY", "<!--"], "markup declaration")?;
+                if self.scanner.consume_literal("]") {
+                    break;
+                } else if self.scanner.starts_with("<!ENTITY") {
+                    self.parse_entity_declaration()?;
+                } else if self.scanner.starts_with("<!--") {
+                    self.skip_comment()?;
+                } else if self.scanner.starts_with("<!") || self.scanner.starts_with("<?") {
+                    self.skip_markup_declaration()?;
+                } else {
+                    match self.scanner.peek_char() {
+                        Some('%') => return Err("Parameter entity references are not supported".into()),
+                        Some(c) => return Err(format!("Unexpected '{}' in DOCTYPE internal subset", c).into()),
+                        None => return Err(XmlError::UnexpectedEof { expected: "']'", inside: None }),
+                    }
+                }
+            }
+            self.scanner.skip_whitespace();
+        }
+
+        self.expect_char('>')
+    }
+
+    fn parse_entity_declaration(&mut self) -> XmlResult<()> {
+        self.scanner.consume_literal("<!ENTITY");
+        self.expect_whitespace()?;
+        let parameter = self.scanner.consume_literal("%");
+        if parameter {
+            self.expect_whitespace()?;
+        }
+        let name = self.parse_name()?;
+        self.expect_whitespace()?;
+
+        if self.at_external_id() {
+            return Err(XmlError::ExternalEntity { position: self.location() });
+        }
+        if parameter {
+            return Err("Parameter entities are not supported".into());
+        }
+
+        let quote = match self.scanner.peek_char() {
+            Some(q @ ('"' | '\'')) => q,
+            Some(c) => return Err(self.unexpected_char(format!("quoted value for entity '{}'", name), c)),
+            None => return Err(XmlError::UnexpectedEof { expected: "entity value", inside: None }),
+        };
+        self.scanner.next_char();
+        let value_start = self.scanner.position();
+
+        let mut raw = String::new();
+        loop {
+            match self.scanner.next_char() {
+                Some(c) if c == quote => break,
+                Some(c) => raw.push(c),
+                None => return Err(XmlError::UnexpectedEof { expected: "closing quote of entity value", inside: Some(XmlConstruct::String) }),
+            }
+        }
+        self.scanner.skip_whitespace();
+        self.expect_char('>')?;
+
+        // A value may not refer to any other entity, declared or not, which
+        // rules out exponential "billion laughs" expansion. Only the predefined
+        // entities and character references are decoded.
+        let value = match unescape_xml_text(&raw, &HashMap::new(), self.options.reject_invalid_chars) {
+            Err(XmlError::UnknownEntity { name: referenced, .. }) => {
+                let reference = format!("&{};", referenced);
+                let offset = value_start + raw[..raw.find(&reference).unwrap_or(0)].chars().count();
+                let (line, column) = self.scanner.line_column_at(offset);
+                return Err(XmlError::Syntax {
+                    message: format!("Entity '{}' refers to entity '{}', which is not supported", name, referenced),
+                    position: Position { line, column, offset },
+                });
+            }
+            result => result?,
+        };
+        // The first declaration of an entity is binding
+        self.entities.entry(name).or_insert(value);
+        Ok(())
+    }
+
+    // Skips `<!ELEMENT ...>`, `<!ATTLIST ...>`, `<?pi ...?>` and the like,
+    // ignoring any `>` inside quoted literals
+    fn skip_markup_declaration(&mut self) -> XmlResult<()> {
+        let mut quote = None;
+        while let Some(c) = self.scanner.next_char() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None if c == '>' => return Ok(()),
+                None => {}
+            }
+        }
+        Err(XmlError::UnexpectedEof { expected: "'>'", inside: None })
+    }
+
+    fn at_external_id(&self) -> bool {
+        self.scanner.starts_with("SYSTEM") || self.scanner.starts_with("PUBLIC")
+    }
+
+    fn expect_whitespace(&mut self) -> XmlResult<()> {
+        match self.scanner.peek_char() {
+            Some(c) if c.is_whitespace() => {
+                self.scanner.skip_whitespace();
+                Ok(())
+            }
+            Some(c) => Err(self.unexpected_char("whitespace", c)),
+            None => Err(XmlError::UnexpectedEof { expected: "whitespace", inside: None }),
+        }
+    }
+
+    fn parse_element(&mut self) -> XmlResult<XmlNode> {
+        self.scanner.skip_whitespace();
+        self.expect_char('<')?;
+        
+        let tag = self.parse_tag_name()?;
+        let mut node = XmlNode::new(String::new());
+        node.tag = self.intern(tag);
+        
+        // Parse attributes
+        node.attributes = self.parse_attributes()?;
+        
+        // Check if it's a self-closing tag
+        self.scanner.skip_whitespace();
+        if self.scanner.peek_char() == Some('/') {
+            self.scanner.next_char();
+            // Tolerate `<br/ >`; anything else between `/` and `>` is an error
+            self.scanner.skip_whitespace();
+            self.expect_char('>')?;
+            return Ok(node);
+        }
+        
+        self.expect_char('>')?;
+
+        if self.options.raw_elements.contains(&*node.tag) {
+            let raw = self.parse_raw_content(&node.tag)?;
+            if !self.discard && !raw.is_empty() {
+                node.text = Some(raw);
+            }
+            return Ok(node);
+        }
+        
+        // Parse content (text and child nodes). Text and CDATA sections that
+        // follow each other form one run, stored once the next tag starts.
+        let mut blank_text = None;
+        let mut run: Option<TextRun> = None;
+        loop {
+            if self.options.trim_text && run.is_none() {
+                self.scanner.skip_whitespace();
+            }
+
+            if self.scanner.peek_char().is_none() {
+                return Err(XmlError::UnexpectedEof { expected: "closing tag", inside: None });
+            }
+
+            self.check_truncated(&["<!--", "<![CDATA["], "markup")?;
+
+            // Dropped without ending the text run, so `a<!-- -->b` reads as "ab"
+            if self.scanner.starts_with("<!--") {
+                self.skip_comment()?;
+                continue;
+            }
+            if self.scanner.starts_with("<?") {
+                self.skip_processing_instruction()?;
+                continue;
+            }
+
+            if self.scanner.starts_with("<![CDATA[") {
+                let start = self.scanner.position();
+                let data = self.parse_cdata()?;
+                let run = run.get_or_insert_with(|| TextRun::new(start));
+                run.text.push_str(&data);
+                run.has_cdata = true;
+                run.cdata_end = run.text.len();
+                continue;
+            }
+            
+            if self.scanner.peek_char() == Some('<') {
+                if let Some(run) = run.take() {
+                    self.store_text(&mut node, run, &mut blank_text);
+                }
+                if self.scanner.peek_next_char() == Some('/') {
+                    let close_start = self.scanner.position();
+                    self.scanner.next_char(); // Skip '<'
+                    self.scanner.next_char(); // Skip '/'
+                    let close_tag = self.parse_tag_name()?;
+                    
+                    if close_tag != *node.tag {
+                        // Reported at the end tag rather than past its name
+                        self.scanner.set_position(close_start);
+                        return Err(XmlError::MismatchedTag {
+                            expected: node.tag.to_string(),
+                            found: close_tag,
+                            position: self.location(),
+                        });
+                    }
+                    
+                    // `</item >` is well-formed; only the name has to match
+                    self.scanner.skip_whitespace();
+                    self.expect_char('>')?;
+                    break;
+                } else {
+                    let child = self.parse_element()?;
+                    if !self.discard {
+                        node.children.push(child);
+                    }
+                }
+            } else {
+                let start = self.scanner.position();
+                let text = self.parse_text()?;
+                let run = run.get_or_insert_with(|| TextRun::new(start));
+                run.text.push_str(&text);
+            }
+        }
+
+        // Whitespace between child elements is formatting, but a leaf whose
+        // whole content is whitespace keeps it when trimming is off
+        if !self.options.trim_text && node.text.is_none() && node.children.is_empty() {
+            node.text = blank_text;
+        }
+        
+        Ok(node)
+    }
+    
+    fn store_text(&mut self, node: &mut XmlNode, run: TextRun, blank_text: &mut Option<String>) {
+        if self.discard {
+            return;
+        }
+        // A CDATA section is content even when it holds only whitespace
+        if !run.has_cdata && run.text.trim().is_empty() {
+            blank_text.get_or_insert(run.text);
+            return;
+        }
+        if node.text.is_some() && self.warnings.is_some() {
+            self.warn(run.start, format!("Earlier text of <{}> replaced by this text", node.tag));
+        }
+        let mut text = run.text;
+        if self.options.trim_text {
+            text.truncate(run.cdata_end + text[run.cdata_end..].trim_end().len());
+        }
+        node.text = Some(text);
+    }
+
+    // `<![CDATA[...]]>`, returned verbatim
+    fn parse_cdata(&mut self) -> XmlResult<String> {
+        self.scanner.consume_literal("<![CDATA[");
+        let mut data = String::new();
+        while !self.scanner.consume_literal("]]>") {
+            let c = self.scanner.next_char().ok_or(XmlError::UnexpectedEof {
+                expected: "']]>'",
+                inside: Some(XmlConstruct::Cdata),
+            })?;
+            self.check_char(c)?;
+            if !self.discard {
+                data.push(c);
+            }
+        }
+        Ok(data)
+    }
+
+    fn parse_tag_name(&mut self) -> XmlResult<String> {
+        let name = self.parse_name()?;
+        if self.options.lowercase_names {
+            return Ok(name.to_lowercase());
+        }
+        Ok(name)
+    }
+
+    // A name exactly as written, for names that `lowercase_names` must not
+    // touch, such as entity names
+    fn parse_name(&mut self) -> XmlResult<String> {
+        let mut name = String::new();
+
+        match self.scanner.peek_char() {
+            Some(c) if is_name_start_char(c) => name.push(self.scanner.next_char().unwrap()),
+            Some(c) if is_name_char(c) => {
+                return Err(format!("Name cannot start with '{}'", c).into());
+            }
+            Some(_) => return Err("Expected tag name".into()),
+            None => return Err(XmlError::UnexpectedEof { expected: "name", inside: None }),
+        }
+        
+        while let Some(c) = self.scanner.peek_char() {
+            if is_name_char(c) {
+                name.push(self.scanner.next_char().unwrap());
+            } else {
+                break;
+            }
+        }
+
+        Ok(name)
+    }
+    
+    fn parse_attributes(&mut self) -> XmlResult<HashMap<Arc<str>, String>> {
+        let mut attributes = HashMap::new();
+        let mut count = 0;
+        
+        loop {
+            self.scanner.skip_whitespace();
+            
+            if self.scanner.peek_char() == Some('>') || self.scanner.peek_char() == Some('/') {
+                break;
+            }
+
+            count += 1;
+            if count > self.options.max_attributes {
+                return Err(self.limit_exceeded(XmlLimit::Attributes, self.options.max_attributes));
+            }
+            
+            let start = self.scanner.position();
+            let name = self.parse_attribute_name()?;
+            if name.chars().count() > self.options.max_attribute_name_length {
+                return Err(self.limit_exceeded(XmlLimit::AttributeNameLength, self.options.max_attribute_name_length));
+            }
+            self.scanner.skip_whitespace();
+            match self.scanner.peek_char() {
+                Some('=') => {
+                    self.scanner.next_char();
+                }
+                Some(_) if self.options.allow_valueless_attributes => {
+                    if self.warnings.is_some() {
+                        self.warn(start, format!("Attribute '{}' has no value", name));
+                    }
+                    if !self.discard {
+                        self.insert_attribute(&mut attributes, start, name, String::new());
+                    }
+                    continue;
+                }
+                Some(c) => {
+                    return Err(self.unexpected_char(format!("'=' after attribute name '{}'", name), c));
+                }
+                None => return Err(XmlError::UnexpectedEof { expected: "'='", inside: None }),
+            }
+            self.scanner.skip_whitespace();
+            let quote = match self.scanner.next_char() {
+                Some(c @ ('"' | '\'')) => c,
+                Some(c) => {
+                    self.scanner.set_position(self.scanner.position() - 1);
+                    return Err(self.unexpected_char(format!("quoted value for attribute '{}'", name), c));
+                }
+                None => return Err(XmlError::UnexpectedEof { expected: "attribute value", inside: None }),
+            };
+            
+            let value = self.parse_attribute_value(quote)?;
+            if !self.discard {
+                self.insert_attribute(&mut attributes, start, name, value);
+            }
+        }
+        
+        Ok(attributes)
+    }
+
+    fn insert_attribute(&mut self, attributes: &mut HashMap<Arc<str>, String>, start: usize, name: String, value: String) {
+        if self.warnings.is_some() && attributes.contains_key(name.as_str()) {
+            self.warn(start, format!("Duplicate attribute '{}', keeping the last value", name));
+        }
+        let name = self.intern(name);
+        attributes.insert(name, value);
+    }
+    
+    // Reads everything up to the `</tag>` matching an already opened raw element.
+    // Nested `<tag>` opens are counted so their closes don't end the content early.
+    fn parse_raw_content(&mut self, tag: &str) -> XmlResult<String> {
+        let open = format!("<{}", tag);
+        let close = format!("</{}", tag);
+        let mut raw = String::new();
+        let mut depth = 0;
+
+        loop {
+            if self.scanner.is_at_end() {
+                return Err(XmlError::UnexpectedEof { expected: "closing tag", inside: None });
+            }
+
+            if self.raw_tag_starts(&close) && self.raw_name_ends(close.chars().count(), false) {
+                let start = self.scanner.position();
+                self.scanner.set_position(start + close.chars().count());
+                self.scanner.skip_whitespace();
+                if self.scanner.peek_char() == Some('>') {
+                    self.scanner.next_char();
+                    if depth == 0 {
+                        return Ok(raw);
+                    }
+                    depth -= 1;
+                }
+                raw.extend(self.scanner.consumed_since(start));
+            } else if self.raw_tag_starts(&open) && self.raw_name_ends(open.chars().count(), true) {
+                let self_closing = self.skip_raw_tag(&mut raw);
+                if !self_closing {
+                    depth += 1;
+                }
+            } else if let Some(c) = self.scanner.next_char() {
+                raw.push(c);
+            }
+        }
+    }
+
+    // Whether `<tag` or `</tag` is at the current position. Under
+    // `lowercase_names` the tag has been lowercased, so the source's may be
+    // in any case.
+    fn raw_tag_starts(&self, literal: &str) -> bool {
+        if !self.options.lowercase_names {
+            return self.scanner.starts_with(literal);
+        }
+        let rest = self.scanner.remaining();
+        literal.chars().enumerate().all(|(i, expected)| {
+            rest.get(i).is_some_and(|c| c.to_lowercase().eq(expected.to_lowercase()))
+        })
+    }
+
+    // Whether the name in a `<tag` or `</tag` at the current position ends after
+    // `len` characters, rather than continuing as e.g. `<tagline`
+    fn raw_name_ends(&self, len: usize, opening: bool) -> bool {
+        match self.scanner.remaining().get(len) {
+            Some(&c) => c == '>' || c.is_whitespace() || (opening && c == '/'),
+            None => false,
+        }
+    }
+
+    // Copies a nested start tag into `raw` up to its `>`, skipping quoted
+    // attribute values. Returns whether the tag was self-closing.
+    fn skip_raw_tag(&mut self, raw: &mut String) -> bool {
+        let mut quote = None;
+        let mut previous = None;
+        while let Some(c) = self.scanner.next_char() {
+            raw.push(c);
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None if c == '>' => return previous == Some('/'),
+                None => {}
+            }
+            previous = Some(c);
+        }
+        false
+    }
+
+    // Element name rules, with errors that point at the attribute rather than a
+    // later missing '='
+    fn parse_attribute_name(&mut self) -> XmlResult<String> {
+        match self.scanner.peek_char() {
+            Some(c) if is_name_start_char(c) => {}
+            Some(c) => return Err(format!("Invalid attribute name starting with '{}'", c).into()),
+            None => return Err(XmlError::UnexpectedEof { expected: "attribute name", inside: None }),
+        }
+
+        let name = self.parse_tag_name()?;
+        match self.scanner.peek_char() {
+            Some(c) if !matches!(c, '=' | '>' | '/') && !c.is_whitespace() => {
+                Err(format!("Invalid character '{}' in attribute name '{}'", c, name).into())
+            }
+            _ => Ok(name),
+        }
+    }
+
+    fn intern(&mut self, name: String) -> Arc<str> {
+        if !self.options.intern_names {
+            return name.into();
+        }
+        if let Some(shared) = self.names.get(name.as_str()) {
+            return Arc::clone(shared);
+        }
+        let shared: Arc<str> = name.into();
+        self.names.insert(Arc::clone(&shared));
+        shared
+    }
+
+    fn parse_attribute_value(&mut self, quote: char) -> XmlResult<String> {
+        let mut value = String::new();
+        let mut length = 0;
+        
+        while let Some(c) = self.scanner.next_char() {
+            // Only the opening quote ends the value, so `"it's"` keeps its
+            // apostrophe; `&quot;` and `&apos;` are decoded below
+            if c == quote {
+                return unescape_xml_text(&value, &self.entities, self.options.reject_invalid_chars);
+            }
+
+            // Checked on the raw value so oversized input is rejected before it is buffered
+            length += 1;
+            if length > self.options.max_attribute_value_length {
+                return Err(self.limit_exceeded(XmlLimit::AttributeValueLength, self.options.max_attribute_value_length));
+            }
+            self.check_char(c)?;
+            if c == '<' && self.options.reject_lt_in_attribute_values {
+                self.scanner.set_position(self.scanner.position() - 1);
+                return Err("'<' is not allowed in attribute values".into());
+            }
+            value.push(c);
+        }
+        
+        Err(XmlError::UnexpectedEof { expected: "closing quote of attribute value", inside: Some(XmlConstruct::String) })
+    }
+    
+    // Character data up to the next tag, with entity references decoded.
+    // Kept even under `validate` so unknown references are still caught.
+    fn parse_text(&mut self) -> XmlResult<String> {
+        let mut text = String::new();
+        
+        while let Some(c) = self.scanner.peek_char() {
+            if c == '<' {
+                break;
+            }
+            self.scanner.next_char();
+            self.check_char(c)?;
+            text.push(c);
+        }
+        
+        if !text.contains('&') {
+            return Ok(text);
+        }
+        // `&am;` cut short at the end of the input, rather than a reference
+        // that is missing its ';'
+        if self.scanner.is_at_end() && text.rfind('&').is_some_and(|amp| !text[amp..].contains(';')) {
+            return Err(XmlError::UnexpectedEof { expected: "';'", inside: Some(XmlConstruct::EntityReference) });
+        }
+        unescape_xml_text(&text, &self.entities, self.options.reject_invalid_chars)
+    }
+    
+    // `c` has just been consumed; on error the scanner is moved back onto it
+    // so the reported location is the character's own
+    fn check_char(&mut self, c: char) -> XmlResult<()> {
+        if !self.options.reject_invalid_chars || is_xml_char(c) {
+            return Ok(());
+        }
+        self.scanner.set_position(self.scanner.position() - 1);
+        Err(XmlError::InvalidChar { found: c, position: self.location() })
+    }
+
+    fn limit_exceeded(&self, limit: XmlLimit, max: usize) -> XmlError {
+        XmlError::LimitExceeded { limit, max, position: self.location() }
+    }
+
+    // Leaves an unexpected character unread, so the error points at it
+    fn expect_char(&mut self, expected: char) -> XmlResult<()> {
+        match self.scanner.peek_char() {
+            Some(c) if c == expected => {
+                self.scanner.next_char();
+                Ok(())
+            }
+            Some(c) => Err(self.unexpected_char(format!("'{}'", expected), c)),
+            None => Err(XmlError::UnexpectedEof { expected: describe_char(expected), inside: None }),
+        }
+    }
+
+    fn unexpected_char(&self, expected: impl Into<String>, found: char) -> XmlError {
+        XmlError::UnexpectedChar { expected: expected.into(), found, position: self.location() }
+    }
+}
+
+// Re-indents an XML document by `indent` spaces per level, as formatted by
+// `XmlNode::to_xml_pretty`. The prolog, comments and processing instructions
+// are dropped, and since an `XmlNode` keeps a single text, mixed content
+// comes out with all of an element's text ahead of its children.
+pub fn reformat_xml(input: &str, indent: usize) -> XmlResult<String> {
+    Ok(XmlParser::new(input).parse()?.to_xml_pretty(indent))
+}
+
+// Names the delimiters the parser expects, for `XmlError::UnexpectedEof`
+
+fn describe_char(c: char) -> &'static str {
+    match c {
+        '<' => "'<'",
+        '>' => "'>'",
+        '=' => "'='",
+        '"' => "'\"'",
+        _ => "more input",
+    }
+}
+
+// `#65` or `#x41` from `&#65;` / `&#x41;`. Surrogates and code points past
+// U+10FFFF have no `char` and are rejected along with malformed digits, as
+// is any other non-XML character when `reject_invalid_chars` is set.
+fn decode_character_reference(reference: &str, reject_invalid_chars: bool) -> XmlResult<char> {
+    let digits = &reference[1..];
+    let code = match digits.strip_prefix('x') {
+        Some(hex) if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) => u32::from_str_radix(hex, 16).ok(),
+        None if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) => digits.parse().ok(),
+        _ => None,
+    };
+    code.and_then(char::from_u32)
+        .filter(|&c| !reject_invalid_chars || is_xml_char(c))
+        .ok_or_else(|| format!("Invalid character reference '&{};'", reference).into())
+}
+
+// Decodes the predefined entities (`&amp;`, `&lt;`, `&gt;`, `&quot;`, `&apos;`),
+// any declared in the DTD internal subset, and character references
+fn unescape_xml_text(text: &str, entities: &HashMap<String, String>, reject_invalid_chars: bool) -> XmlResult<String> {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let reference = &rest[start + 1..];
+        let end = reference
+            .find(';')
+            .ok_or_else(|| "Unterminated entity reference".to_string())?;
+
+        match &reference[..end] {
+            "amp" => decoded.push('&'),
+            "lt" => decoded.push('<'),
+            "gt" => decoded.push('>'),
+            "quot" => decoded.push('"'),
+            "apos" => decoded.push('\''),
+            name if name.starts_with('#') => decoded.push(decode_character_reference(name, reject_invalid_chars)?),
+            name => match entities.get(name) {
+                Some(value) => decoded.push_str(value),
+                None => return Err(XmlError::UnknownEntity { name: name.to_string(), position: UNLOCATED }),
+            },
+        }
+        rest = &reference[end + 1..];
+    }
+
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(is_name_start_char) && chars.all(is_name_char)
+}
+
+// The XML 1.0 `Char` production
+fn is_xml_char(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\r' | '\u{20}'..='\u{D7FF}' | '\u{E000}'..='\u{FFFD}' | '\u{10000}'..)
+}
+
+// NameStartChar from the XML 1.0 (Fifth Edition) spec
+fn is_name_start_char(c: char) -> bool {
+    matches!(c,
+        ':' | 'A'..='Z' | '_' | 'a'..='z'
+        | '\u{C0}'..='\u{D6}' | '\u{D8}'..='\u{F6}' | '\u{F8}'..='\u{2FF}'
+        | '\u{370}'..='\u{37D}' | '\u{37F}'..='\u{1FFF}' | '\u{200C}'..='\u{200D}'
+        | '\u{2070}'..='\u{218F}' | '\u{2C00}'..='\u{2FEF}' | '\u{3001}'..='\u{D7FF}'
+        | '\u{F900}'..='\u{FDCF}' | '\u{FDF0}'..='\u{FFFD}' | '\u{10000}'..='\u{EFFFF}')
+}
+
+// NameChar from the XML 1.0 (Fifth Edition) spec
+fn is_name_char(c: char) -> bool {
+    is_name_start_char(c)
+        || matches!(c,
+            '-' | '.' | '0'..='9' | '\u{B7}'
+            | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}')
+}
+
+#[derive(Debug, Clone)]
+pub enum JsonValue {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    // A number written without '.', 'e' or 'E' that fits in an i64, kept
+    // exact instead of rounded to an f64
+    Integer(i64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(HashMap<String, JsonValue>),
+}
+
+impl JsonValue {
+    pub fn is_null(&self) -> bool {
+        matches!(self, JsonValue::Null)
+    }
+
+    pub fn is_bool(&self) -> bool {
+        matches!(self, JsonValue::Boolean(_))
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, JsonValue::Number(_) | JsonValue::Integer(_))
+    }
+
+    pub fn is_string(&self) -> bool {
+        matches!(self, JsonValue::String(_))
+    }
+
+    pub fn is_array(&self) -> bool {
+        matches!(self, JsonValue::Array(_))
+    }
+
+    pub fn is_object(&self) -> bool {
+        matches!(self, JsonValue::Object(_))
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            JsonValue::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(n) => Some(*n),
+            JsonValue::Integer(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            JsonValue::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            JsonValue::Array(arr) => Some(arr),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&HashMap<String, JsonValue>> {
+        match self {
+            JsonValue::Object(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    pub fn as_array_mut(&mut self) -> Option<&mut Vec<JsonValue>> {
+        match self {
+            JsonValue::Array(arr) => Some(arr),
+            _ => None,
+        }
+    }
+
+    pub fn as_object_mut(&mut self) -> Option<&mut HashMap<String, JsonValue>> {
+        match self {
+            JsonValue::Object(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    // Object members in no particular order; empty for anything but an object
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.as_object().into_iter().flat_map(|obj| obj.keys().map(String::as_str))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &JsonValue> {
+        self.as_object().into_iter().flat_map(|obj| obj.values())
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &JsonValue)> {
+        self.as_object()
+            .into_iter()
+            .flat_map(|obj| obj.iter().map(|(key, value)| (key.as_str(), value)))
+    }
+
+    // Number of elements or members; 0 for scalars
+    pub fn len(&self) -> usize {
+        match self {
+            JsonValue::Array(arr) => arr.len(),
+            JsonValue::Object(obj) => obj.len(),
+            _ => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Scalars as text: strings as-is, numbers and booleans as they print in JSON
+    pub fn coerce_string(&self) -> Option<String> {
+        match self {
+            JsonValue::String(s) => Some(s.clone()),
+            JsonValue::Number(_) | JsonValue::Integer(_) | JsonValue::Boolean(_) => Some(self.to_string()),
+            _ => None,
+        }
+    }
+
+    // Whole numbers and strings holding one, such as "42" or " -7 "
+    pub fn as_i64_lenient(&self) -> Option<i64> {
+        let n = match self {
+            JsonValue::Number(n) => *n,
+            JsonValue::Integer(n) => return Some(*n),
+            JsonValue::String(s) => {
+                let s = s.trim();
+                if let Ok(i) = s.parse::<i64>() {
+                    return Some(i);
+                }
+                s.parse::<f64>().ok()?
+            }
+            _ => return None,
+        };
+        // The bounds are exact powers of two, so the cast below can't saturate
+        if n.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&n) {
+            Some(n as i64)
+        } else {
+            None
+        }
+    }
+
+    // Booleans, 1/0, and the strings "true"/"false"/"1"/"0" in any case
+    pub fn as_bool_lenient(&self) -> Option<bool> {
+        match self {
+            JsonValue::Boolean(b) => Some(*b),
+            JsonValue::Number(n) if *n == 1.0 => Some(true),
+            JsonValue::Number(n) if *n == 0.0 => Some(false),
+            JsonValue::Integer(1) => Some(true),
+            JsonValue::Integer(0) => Some(false),
+            JsonValue::String(s) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" | "1" => Some(true),
+                "false" | "0" => Some(false),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for JsonValue {
+    fn from(b: bool) -> Self {
+        JsonValue::Boolean(b)
+    }
+}
+
+// `Integer` and `Number` compare by numeric value, so `1` equals `1.0`;
+// everything else compares structurally
+impl PartialEq for JsonValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (JsonValue::Null, JsonValue::Null) => true,
+            (JsonValue::Boolean(a), JsonValue::Boolean(b)) => a == b,
+            (JsonValue::Number(a), JsonValue::Number(b)) => a == b,
+            (JsonValue::Integer(a), JsonValue::Integer(b)) => a == b,
+            (JsonValue::Integer(i), JsonValue::Number(n)) | (JsonValue::Number(n), JsonValue::Integer(i)) => {
+                integer_equals_float(*i, *n)
+            }
+            (JsonValue::String(a), JsonValue::String(b)) => a == b,
+            (JsonValue::Array(a), JsonValue::Array(b)) => a == b,
+            (JsonValue::Object(a), JsonValue::Object(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+// Exact, unlike `i as f64 == n`, which rounds large integers
+fn integer_equals_float(i: i64, n: f64) -> bool {
+    // The bounds are exact powers of two, so the cast below can't saturate
+    n.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&n) && n as i64 == i
+}
+
+impl From<f64> for JsonValue {
+    fn from(n: f64) -> Self {
+        JsonValue::Number(n)
+    }
+}
+
+impl From<i64> for JsonValue {
+    fn from(n: i64) -> Self {
+        JsonValue::Integer(n)
+    }
+}
+
+impl From<&str> for JsonValue {
+    fn from(s: &str) -> Self {
+        JsonValue::String(s.to_string())
+    }
+}
+
+impl From<String> for JsonValue {
+    fn from(s: String) -> Self {
+        JsonValue::String(s)
+    }
+}
+
+impl From<Vec<JsonValue>> for JsonValue {
+    fn from(arr: Vec<JsonValue>) -> Self {
+        JsonValue::Array(arr)
+    }
+}
+
+impl From<HashMap<String, JsonValue>> for JsonValue {
+    fn from(obj: HashMap<String, JsonValue>) -> Self {
+        JsonValue::Object(obj)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EscapeOptions {
+    // `&` and `<` are always escaped; `>` is escaped after `]]` even when `gt` is off
+    pub gt: bool,
+    pub quot: bool,
+    pub apos: bool,
+}
+
+impl EscapeOptions {
+    pub fn all() -> Self {
+        EscapeOptions {
+            gt: true,
+            quot: true,
+            apos: true,
+        }
+    }
+}
+
+// Text written for `true`, `false` and `null`; a `None` null renders as an empty element
+#[derive(Debug, Clone)]
+pub struct ScalarText {
+    pub true_text: String,
+    pub false_text: String,
+    pub null_text: Option<String>,
+}
+
+impl Default for ScalarText {
+    fn default() -> Self {
+        ScalarText {
+            true_text: "true".to_string(),
+            false_text: "false".to_string(),
+            null_text: None,
+        }
+    }
+}
+
+// How `to_xml` writes `[]` and `{}`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EmptyCollectionStyle {
+    // `<a></a>`
+    #[default]
+    OpenClose,
+    // `<a/>`
+    SelfClosing,
+    // `<a type="array"/>` or `<a type="object"/>`, readable by `ToJsonOptions::type_hints`
+    TypeMarker,
+    // Leave the element out; the root element falls back to `<root/>`
+    Omit,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ToXmlOptions {
+    pub escape: EscapeOptions,
+    pub scalars: ScalarText,
+    // Render `{"k": [a, b]}` as `<k>a</k><k>b</k>` instead of nesting `<item>`s under `<k>`
+    pub repeat_key_for_arrays: bool,
+    // Add `type="number"`, `type="array"`, ... to every element so the JSON
+    // types can be restored with `ToJsonOptions::type_hints`
+    pub type_hints: bool,
+    // Use the key of a single-key top-level object as the root element instead of `<root>`
+    pub single_key_root: bool,
+    pub empty_arrays: EmptyCollectionStyle,
+    pub empty_objects: EmptyCollectionStyle,
+    // Write an `"@attributes": [["name", "value"], ...]` member as attributes
+    // on its element, in array order, or an `"@attributes": {"name": "value"}`
+    // member (as `to_json` produces) in name order. Any other shape is written
+    // as a child element, which `try_to_xml` rejects as an invalid name.
+    pub attribute_pairs: bool,
+    // (prefix, uri) namespace declarations written on the root element; an
+    // empty prefix declares the default namespace
+    pub root_namespaces: Vec<(String, String)>,
+    // End the document with '\n', as many tools expect of a file
+    pub trailing_newline: bool,
+    // Write strings containing `<`, `>` or `&` as CDATA sections rather than
+    // escaping each of those characters
+    pub cdata_for_markup: bool,
+    // Write a string, number or boolean `"#text"` member (as `to_json` produces
+    // next to attributes or children) as the element's own text, ahead of its
+    // children. A `#text` of any other shape is written as a child element.
+    pub text_member: bool,
+    // Element names for array items by the array's key, e.g. "books" => "book"
+    pub item_tags: HashMap<String, String>,
+    // Without an `item_tags` entry, name items after the array's key minus a
+    // trailing 's' ("books" => "book"; "address" and "s" keep "item")
+    pub singular_item_tags: bool,
+}
+
+pub const SOAP_1_1_NAMESPACE: &str = "http://schemas.xmlsoap.org/soap/envelope/";
+
+#[derive(Debug, Clone)]
+pub struct SoapEnvelopeOptions {
+    pub namespace: String,
+    // Element inside <soap:Body> that holds the payload, unless
+    // `single_key_root` takes the tag from the payload itself
+    pub body_element: String,
+}
+
+impl Default for SoapEnvelopeOptions {
+    fn default() -> Self {
+        SoapEnvelopeOptions {
+            namespace: SOAP_1_1_NAMESPACE.to_string(),
+            body_element: "root".to_string(),
+        }
+    }
+}
+
+// Why `try_to_xml` refused a value; `pointer` locates it in the JSON document
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToXmlError {
+    InvalidName { pointer: String, name: String },
+    InvalidCharacter { pointer: String, character: char },
+}
+
+impl fmt::Display for ToXmlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToXmlError::InvalidName { pointer, name } => {
+                write!(f, "Key '{}' at '{}' is not a valid XML name", name, pointer)
+            }
+            ToXmlError::InvalidCharacter { pointer, character } => {
+                write!(f, "Character U+{:04X} at '{}' cannot appear in XML", *character as u32, pointer)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ToXmlError {}
+
+impl JsonValue {
+    pub fn to_xml(&self) -> String {
+        self.to_xml_with_options(&ToXmlOptions::default())
+    }
+
+    pub fn to_serde(&self) -> Value {
+        match self {
+            JsonValue::Null => Value::Null,
+            JsonValue::Boolean(b) => Value::Bool(*b),
+            // Whole numbers map to serde integers so they print as `42`, not `42.0`
+            JsonValue::Number(n) if n.fract() == 0.0 && n.abs() < 9007199254740992.0 => {
+                Value::from(*n as i64)
+            }
+            // serde_json can't hold NaN or infinity either
+            JsonValue::Number(n) => serde_json::Number::from_f64(*n)
+                .map(Value::Number)
+                .unwrap_or(Value::Null),
+            JsonValue::Integer(n) => Value::from(*n),
+            JsonValue::String(s) => Value::String(s.clone()),
+            JsonValue::Array(arr) => Value::Array(arr.iter().map(JsonValue::to_serde).collect()),
+            JsonValue::Object(obj) => {
+                let mut map = Map::new();
+                for (key, value) in obj {
+                    map.insert(key.clone(), value.to_serde());
+                }
+                Value::Object(map)
+            }
+        }
+    }
+
+    pub fn to_xml_with_options(&self, options: &ToXmlOptions) -> String {
+        let mut xml = String::new();
+        self.write_xml_with_options(&mut xml, options)
+            .expect("writing to a String cannot fail");
+        xml
+    }
+
+    // Streams the XML to `w` instead of building a String
+    pub fn write_xml<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        self.write_xml_with_options(w, &ToXmlOptions::default())
+    }
+
+    pub fn write_xml_with_options<W: fmt::Write>(&self, w: &mut W, options: &ToXmlOptions) -> fmt::Result {
+        self.write_xml_root(w, "root", options)?;
+        if options.trailing_newline {
+            w.write_char('\n')?;
+        }
+        Ok(())
+    }
+
+    // Wraps the XML for this value in a SOAP envelope:
+    // `<soap:Envelope xmlns:soap=".."><soap:Body>payload</soap:Body></soap:Envelope>`.
+    // The payload is written as by `to_xml_with_options`, so `single_key_root`
+    // and `root_namespaces` apply to it.
+    pub fn to_soap_envelope(&self, soap: &SoapEnvelopeOptions, options: &ToXmlOptions) -> String {
+        let namespace_escape = EscapeOptions { quot: true, ..options.escape };
+        let mut xml = format!(
+            "<soap:Envelope xmlns:soap=\"{}\"><soap:Body>",
+            escape_xml_text(&soap.namespace, &namespace_escape)
+        );
+        self.write_xml_root(&mut xml, &soap.body_element, options)
+            .expect("writing to a String cannot fail");
+        xml.push_str("</soap:Body></soap:Envelope>");
+        if options.trailing_newline {
+            xml.push('\n');
+        }
+        xml
+    }
+
+    fn write_xml_root<W: fmt::Write>(&self, w: &mut W, default_tag: &str, options: &ToXmlOptions) -> fmt::Result {
+        let namespaces: Vec<(String, String)> = options
+            .root_namespaces
+            .iter()
+            .map(|(prefix, uri)| {
+                let name = if prefix.is_empty() { "xmlns".to_string() } else { format!("xmlns:{}", prefix) };
+                (name, uri.clone())
+            })
+            .collect();
+
+        if options.single_key_root {
+            if let JsonValue::Object(obj) = self {
+                if let Some((key, value)) = obj.iter().next().filter(|_| obj.len() == 1) {
+                    // Repeated keys would give the document several roots
+                    if !(options.repeat_key_for_arrays && value.is_array()) {
+                        return value.write_xml_element(w, key, &namespaces, options);
+                    }
+                }
+            }
+        }
+        self.write_xml_element(w, default_tag, &namespaces, options)
+    }
+
+    // Like `to_xml`, but fails instead of emitting malformed XML for keys that
+    // aren't element names or strings with characters XML can't carry
+    pub fn try_to_xml(&self) -> Result<String, ToXmlError> {
+        self.try_to_xml_with_options(&ToXmlOptions::default())
+    }
+
+    pub fn try_to_xml_with_options(&self, options: &ToXmlOptions) -> Result<String, ToXmlError> {
+        self.check_xml_representable(&mut Vec::new(), options)?;
+        Ok(self.to_xml_with_options(options))
+    }
+
+    // `@attributes` and `#text` members that the options turn into attributes
+    // and text are exempt from the name check; any other shape is written as
+    // an ordinary child element and so fails it
+    fn check_xml_representable(&self, path: &mut Vec<String>, options: &ToXmlOptions) -> Result<(), ToXmlError> {
+        match self {
+            JsonValue::String(s) => match s.chars().find(|&c| !is_xml_char(c)) {
+                Some(character) => Err(ToXmlError::InvalidCharacter { pointer: format_pointer(path), character }),
+                None => Ok(()),
+            },
+            JsonValue::Array(arr) => {
+                for (index, value) in arr.iter().enumerate() {
+                    path.push(index.to_string());
+                    value.check_xml_representable(path, options)?;
+                    path.pop();
+                }
+                Ok(())
+            }
+            JsonValue::Object(obj) => {
+                for (key, value) in sorted_members(obj) {
+                    path.push(key.clone());
+                    let attributes = match key.as_str() {
+                        "@attributes" if options.attribute_pairs => attribute_pairs(value),
+                        _ => None,
+                    };
+                    if let Some(attributes) = attributes {
+                        if let Some(character) = attributes.iter().flat_map(|(_, v)| v.chars()).find(|&c| !is_xml_char(c)) {
+                            return Err(ToXmlError::InvalidCharacter { pointer: format_pointer(path), character });
+                        }
+                    } else if key == "#text" && text_member(obj, options).is_some() {
+                        value.check_xml_representable(path, options)?;
+                    } else if !is_xml_name(key) {
+                        return Err(ToXmlError::InvalidName { pointer: format_pointer(path), name: key.clone() });
+                    } else {
+                        value.check_xml_representable(path, options)?;
+                    }
+                    path.pop();
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn empty_collection_style(&self, options: &ToXmlOptions) -> Option<EmptyCollectionStyle> {
+        match self {
+            JsonValue::Array(arr) if arr.is_empty() => Some(options.empty_arrays),
+            JsonValue::Object(obj) if obj.is_empty() => Some(options.empty_objects),
+            _ => None,
+        }
+    }
+
+    fn is_omitted(&self, options: &ToXmlOptions) -> bool {
+        self.empty_collection_style(options) == Some(EmptyCollectionStyle::Omit)
+    }
+
+    fn write_xml_with_tag<W: fmt::Write>(&self, w: &mut W, tag: &str, options: &ToXmlOptions) -> fmt::Result {
+        self.write_xml_element(w, tag, &[], options)
+    }
+
+    // `extra_attributes` go on this element only, ahead of any others
+    fn write_xml_element<W: fmt::Write>(
+        &self,
+        w: &mut W,
+        tag: &str,
+        extra_attributes: &[(String, String)],
+        options: &ToXmlOptions,
+    ) -> fmt::Result {
+        let empty_style = self.empty_collection_style(options);
+        let self_closing = match self {
+            JsonValue::Null => options.type_hints || options.scalars.null_text.is_none(),
+            _ => empty_style.is_some_and(|style| style != EmptyCollectionStyle::OpenClose),
+        };
+        let type_hint = options.type_hints || empty_style == Some(EmptyCollectionStyle::TypeMarker);
+        let attributes = match self {
+            JsonValue::Object(obj) if options.attribute_pairs => obj.get("@attributes").and_then(attribute_pairs),
+            _ => None,
+        };
+        let text = match self {
+            JsonValue::Object(obj) => text_member(obj, options),
+            _ => None,
+        };
+
+        let attribute_escape = EscapeOptions { quot: true, ..options.escape };
+        write!(w, "<{}", tag)?;
+        for (name, value) in extra_attributes {
+            write!(w, " {}=\"{}\"", name, escape_xml_text(value, &attribute_escape))?;
+        }
+        if type_hint {
+            write!(w, " type=\"{}\"", self.type_name())?;
+        }
+        for (name, value) in attributes.iter().flatten() {
+            write!(w, " {}=\"{}\"", name, escape_xml_text(value, &attribute_escape))?;
+        }
+        if self_closing {
+            return w.write_str("/>");
+        }
+        w.write_char('>')?;
+
+        match self {
+            JsonValue::Array(arr) => {
+                let item_tag = item_tag_for(tag, options);
+                for value in arr.iter().filter(|value| !value.is_omitted(options)) {
+                    w.write_str("  ")?;
+                    value.write_xml_with_tag(w, item_tag, options)?;
+                }
+            }
+            JsonValue::Object(obj) => {
+                if let Some(text) = text {
+                    text.write_xml_text(w, options)?;
+                }
+                let members = obj
+                    .iter()
+                    .filter(|(key, _)| attributes.is_none() || *key != "@attributes")
+                    .filter(|(key, _)| text.is_none() || *key != "#text")
+                    .filter(|(_, value)| !value.is_omitted(options));
+                for (key, value) in members {
+                    match value {
+                        JsonValue::Array(arr) if options.repeat_key_for_arrays => {
+                            for item in arr.iter().filter(|item| !item.is_omitted(options)) {
+                                w.write_str("  ")?;
+                                item.write_xml_with_tag(w, key, options)?;
+                            }
+                        }
+                        _ => {
+                            w.write_str("  ")?;
+                            value.write_xml_with_tag(w, key, options)?;
+                        }
+                    }
+                }
+            }
+            scalar => scalar.write_xml_text(w, options)?,
+        }
+
+        write!(w, "</{}>", tag)
+    }
+
+    fn write_xml_text<W: fmt::Write>(&self, w: &mut W, options: &ToXmlOptions) -> fmt::Result {
+        match self {
+            JsonValue::Null => {
+                let text = options.scalars.null_text.as_deref().unwrap_or_default();
+                w.write_str(&escape_xml_text(text, &options.escape))
+            }
+            JsonValue::Boolean(b) => {
+                let text = if *b { &options.scalars.true_text } else { &options.scalars.false_text };
+                w.write_str(&escape_xml_text(text, &options.escape))
+            }
+            JsonValue::Number(n) => write!(w, "{}", n),
+            JsonValue::Integer(n) => write!(w, "{}", n),
+            JsonValue::String(s) if options.cdata_for_markup && s.contains(['<', '>', '&']) => write_cdata(w, s),
+            // The parser trims leaf text but keeps CDATA as written
+            JsonValue::String(s) if options.type_hints && s.trim() != s => write_cdata(w, s),
+            JsonValue::String(s) => w.write_str(&escape_xml_text(s, &options.escape)),
+            JsonValue::Array(_) | JsonValue::Object(_) => Ok(()),
+        }
+    }
+}
+
+// `[["id", "7"], ["lang", "en"]]` as (name, value) pairs in array order, or
+// `{"id": "7", "lang": "en"}` (the shape `to_json` produces) in name order.
+// None if the value has any other shape, a value isn't a string, number or
+// boolean, or a name isn't a valid XML name.
+fn attribute_pairs(value: &JsonValue) -> Option<Vec<(&str, String)>> {
+    if let JsonValue::Object(obj) = value {
+        return sorted_members(obj)
+            .into_iter()
+            .map(|(name, value)| Some((name.as_str(), value.coerce_string()?)).filter(|_| is_xml_name(name)))
+            .collect();
+    }
+    value
+        .as_array()?
+        .iter()
+        .map(|pair| match pair.as_array()? {
+            [JsonValue::String(name), value] if is_xml_name(name) => Some((name.as_str(), value.coerce_string()?)),
+            _ => None,
+        })
+        .collect()
+}
+
+// The element text held in a `"#text"` member under `ToXmlOptions::text_member`;
+// only a string, number or boolean counts
+fn text_member<'a>(obj: &'a HashMap<String, JsonValue>, options: &ToXmlOptions) -> Option<&'a JsonValue> {
+    obj.get("#text")
+        .filter(|_| options.text_member)
+        .filter(|text| matches!(
+            text,
+            JsonValue::String(_) | JsonValue::Number(_) | JsonValue::Integer(_) | JsonValue::Boolean(_)
+        ))
+}
+
+impl JsonValue {
+    // The name used for `type="..."` hints
+    fn type_name(&self) -> &'static str {
+        match self {
+            JsonValue::Null => "null",
+            JsonValue::Boolean(_) => "boolean",
+            JsonValue::Number(_) | JsonValue::Integer(_) => "number",
+            JsonValue::String(_) => "string",
+            JsonValue::Array(_) => "array",
+            JsonValue::Object(_) => "object",
+        }
+    }
+}
+
+// Tag for the elements of an array written as `<tag>`: an `item_tags` entry,
+// else the singular of `tag` under `singular_item_tags`, else "item"
+fn item_tag_for<'a>(tag: &'a str, options: &'a ToXmlOptions) -> &'a str {
+    if let Some(item_tag) = options.item_tags.get(tag) {
+        return item_tag;
+    }
+    if options.singular_item_tags && !tag.ends_with("ss") {
+        if let Some(singular) = tag.strip_suffix('s').filter(|singular| !singular.is_empty()) {
+            return singular;
+        }
+    }
+    "item"
+}
+
+// A `]]>` inside the text ends one section and starts another between the
+// `]]` and the `>`
+fn write_cdata<W: fmt::Write>(w: &mut W, text: &str) -> fmt::Result {
+    write!(w, "<![CDATA[{}]]>", text.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+fn escape_xml_text(text: &str, options: &EscapeOptions) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            // `]]>` is never allowed in character data
+            '>' if options.gt || escaped.ends_with("]]") => escaped.push_str("&gt;"),
+            '"' if options.quot => escaped.push_str("&quot;"),
+            '\'' if options.apos => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteJsonOptions {
+    // Same layout as `{:#}`
+    pub pretty: bool,
+    // End the document with '\n', as many tools expect of a file
+    pub trailing_newline: bool,
+}
+
+impl fmt::Display for JsonValue {
+    // `{}` writes compact JSON, `{:#}` writes it indented by two spaces
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            self.write_pretty(f, 0)
+        } else {
+            self.write_compact(f)
+        }
+    }
+}
+
+impl JsonValue {
+    // Streaming forms of `{}` and `{:#}`
+    pub fn write_json<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        self.write_compact(w)
+    }
+
+    pub fn write_json_pretty<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        self.write_pretty(w, 0)
+    }
+
+    pub fn write_json_with_options<W: fmt::Write>(&self, w: &mut W, options: &WriteJsonOptions) -> fmt::Result {
+        if options.pretty {
+            self.write_pretty(w, 0)?;
+        } else {
+            self.write_compact(w)?;
+        }
+        if options.trailing_newline {
+            w.write_char('\n')?;
+        }
+        Ok(())
+    }
+
+    pub fn to_json_string_with_options(&self, options: &WriteJsonOptions) -> String {
+        let mut json = String::new();
+        self.write_json_with_options(&mut json, options)
+            .expect("writing to a String cannot fail");
+        json
+    }
+
+    fn write_compact<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        match self {
+            JsonValue::Array(arr) => {
+                w.write_char('[')?;
+                for (i, value) in arr.iter().enumerate() {
+                    if i > 0 {
+                        w.write_char(',')?;
+                    }
+                    value.write_compact(w)?;
+                }
+                w.write_char(']')
+            }
+            JsonValue::Object(obj) => {
+                w.write_char('{')?;
+                for (i, (key, value)) in sorted_members(obj).into_iter().enumerate() {
+                    if i > 0 {
+                        w.write_char(',')?;
+                    }
+                    write_json_string(w, key)?;
+                    w.write_char(':')?;
+                    value.write_compact(w)?;
+                }
+                w.write_char('}')
+            }
+            scalar => scalar.write_scalar(w),
+        }
+    }
+
+    fn write_pretty<W: fmt::Write>(&self, w: &mut W, depth: usize) -> fmt::Result {
+        match self {
+            JsonValue::Array(arr) if !arr.is_empty() => {
+                w.write_char('[')?;
+                for (i, value) in arr.iter().enumerate() {
+                    if i > 0 {
+                        w.write_char(',')?;
+                    }
+                    write_json_newline(w, depth + 1)?;
+                    value.write_pretty(w, depth + 1)?;
+                }
+                write_json_newline(w, depth)?;
+                w.write_char(']')
+            }
+            JsonValue::Object(obj) if !obj.is_empty() => {
+                w.write_char('{')?;
+                for (i, (key, value)) in sorted_members(obj).into_iter().enumerate() {
+                    if i > 0 {
+                        w.write_char(',')?;
+                    }
+                    write_json_newline(w, depth + 1)?;
+                    write_json_string(w, key)?;
+                    w.write_str(": ")?;
+                    value.write_pretty(w, depth + 1)?;
+                }
+                write_json_newline(w, depth)?;
+                w.write_char('}')
+            }
+            other => other.write_compact(w),
+        }
+    }
+
+    fn write_scalar<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        match self {
+            JsonValue::Null => w.write_str("null"),
+            JsonValue::Boolean(b) => write!(w, "{}", b),
+            // JSON has no representation for NaN or infinity
+            JsonValue::Number(n) if !n.is_finite() => w.write_str("null"),
+            // Past 2^53 the digits `{}` writes for a whole number would read
+            // back as an `Integer` of a slightly different value
+            JsonValue::Number(n) if n.fract() == 0.0 && n.abs() >= 9007199254740992.0 => write!(w, "{:e}", n),
+            JsonValue::Number(n) => write!(w, "{}", n),
+            JsonValue::Integer(n) => write!(w, "{}", n),
+            JsonValue::String(s) => write_json_string(w, s),
+            JsonValue::Array(_) => w.write_str("[]"),
+            JsonValue::Object(_) => w.write_str("{}"),
+        }
+    }
+}
+
+// How `to_query_string` writes `{"a": [1, 2]}`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum QueryArrayStyle {
+    // `a=1&a=2`
+    #[default]
+    Repeat,
+    // `a[]=1&a[]=2`
+    Brackets,
+    // `a[0]=1&a[1]=2`
+    Indices,
+}
+
+// Percent-encodes UTF-8 bytes outside `A-Za-z0-9*-._`, with space as '+'
+fn form_urlencode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => encoded.push(byte as char),
+            b' ' => encoded.push('+'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Object members in key order, so the same value always prints the same way
+fn sorted_members(obj: &HashMap<String, JsonValue>) -> Vec<(&String, &JsonValue)> {
+    let mut members: Vec<_> = obj.iter().collect();
+    members.sort_by(|a, b| a.0.cmp(b.0));
+    members
+}
+
+fn write_json_newline<W: fmt::Write>(w: &mut W, depth: usize) -> fmt::Result {
+    w.write_char('\n')?;
+    for _ in 0..depth {
+        w.write_str("  ")?;
+    }
+    Ok(())
+}
+
+fn write_json_string<W: fmt::Write>(w: &mut W, s: &str) -> fmt::Result {
+    w.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => w.write_str("\\\"")?,
+            '\\' => w.write_str("\\\\")?,
+            '\n' => w.write_str("\\n")?,
+            '\r' => w.write_str("\\r")?,
+            '\t' => w.write_str("\\t")?,
+            '\x08' => w.write_str("\\b")?,
+            '\x0c' => w.write_str("\\f")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => w.write_char(c)?,
+        }
+    }
+    w.write_char('"')
+}
+
+impl JsonValue {
+    // RFC 6901 JSON Pointer lookup, e.g. "/users/0/name"
+    pub fn pointer(&self, pointer: &str) -> Option<&JsonValue> {
+        let tokens = parse_pointer(pointer).ok()?;
+        let mut current = self;
+        for token in &tokens {
+            current = match current {
+                JsonValue::Object(obj) => obj.get(token)?,
+                JsonValue::Array(arr) => arr.get(parse_array_index(token).ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+
+    pub fn pointer_mut(&mut self, pointer: &str) -> Option<&mut JsonValue> {
+        let tokens = parse_pointer(pointer).ok()?;
+        self.pointer_tokens_mut(&tokens)
+    }
+
+    fn pointer_tokens_mut(&mut self, tokens: &[String]) -> Option<&mut JsonValue> {
+        let mut current = self;
+        for token in tokens {
+            current = match current {
+                JsonValue::Object(obj) => obj.get_mut(token)?,
+                JsonValue::Array(arr) => arr.get_mut(parse_array_index(token).ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+
+    // Dotted/bracketed path lookup, e.g. "users[0].name"; "" is the value itself
+    pub fn get(&self, path: &str) -> Option<&JsonValue> {
+        let steps = parse_dotted_path(path)?;
+        let mut current = self;
+        for step in &steps {
+            current = match (current, step) {
+                (JsonValue::Object(obj), PathStep::Key(key)) => obj.get(key)?,
+                (JsonValue::Array(arr), PathStep::Index(index)) => arr.get(*index)?,
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+
+    // Like `get` with the path already split, e.g. `&["users", "0", "name"]`;
+    // array elements are addressed by their index
+    pub fn get_path(&self, path: &[&str]) -> Option<&JsonValue> {
+        let mut current = self;
+        for segment in path {
+            current = match current {
+                JsonValue::Object(obj) => obj.get(*segment)?,
+                JsonValue::Array(arr) => arr.get(parse_array_index(segment).ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+
+    pub fn path_exists(&self, path: &str) -> bool {
+        self.get(path).is_some()
+    }
+
+    // Number of values, this one included, at any depth that satisfy `f`
+    pub fn count_matches<F: Fn(&JsonValue) -> bool>(&self, f: F) -> usize {
+        self.count_with(&f)
+    }
+
+    fn count_with<F: Fn(&JsonValue) -> bool>(&self, f: &F) -> usize {
+        let children = match self {
+            JsonValue::Array(arr) => arr.iter().map(|value| value.count_with(f)).sum(),
+            JsonValue::Object(obj) => obj.values().map(|value| value.count_with(f)).sum(),
+            _ => 0,
+        };
+        children + usize::from(f(self))
+    }
+
+    // Every value, this one included, that satisfies `f`; parents come before
+    // their children, and object members are visited in key order
+    pub fn find_all<F: Fn(&JsonValue) -> bool>(&self, f: F) -> Vec<&JsonValue> {
+        let mut found = Vec::new();
+        self.collect_matches(&f, &mut found);
+        found
+    }
+
+    fn collect_matches<'a, F: Fn(&JsonValue) -> bool>(&'a self, f: &F, found: &mut Vec<&'a JsonValue>) {
+        if f(self) {
+            found.push(self);
+        }
+        match self {
+            JsonValue::Array(arr) => arr.iter().for_each(|value| value.collect_matches(f, found)),
+            JsonValue::Object(obj) => {
+                for (_, value) in sorted_members(obj) {
+                    value.collect_matches(f, found);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Every path `get` accepts that leads to a scalar or an empty array/object,
+    // with object members in key order
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+        self.collect_paths(String::new(), &mut paths);
+        paths
+    }
+
+    fn collect_paths(&self, prefix: String, paths: &mut Vec<String>) {
+        match self {
+            JsonValue::Array(arr) if !arr.is_empty() => {
+                for (index, value) in arr.iter().enumerate() {
+                    value.collect_paths(format!("{}[{}]", prefix, index), paths);
+                }
+            }
+            JsonValue::Object(obj) if !obj.is_empty() => {
+                for (key, value) in sorted_members(obj) {
+                    let path = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    value.collect_paths(path, paths);
+                }
+            }
+            _ => paths.push(prefix),
+        }
+    }
+
+    // Flattens to env-var style pairs: {"db": {"hosts": ["a"]}} with "__"
+    // gives "db__hosts__0" => "a". Numbers and booleans are written as JSON,
+    // null as an empty string, and empty arrays and objects are left out.
+    pub fn to_flat_map(&self, separator: &str) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        self.collect_flat(String::new(), separator, &mut map);
+        map
+    }
+
+    fn collect_flat(&self, prefix: String, separator: &str, map: &mut HashMap<String, String>) {
+        let join = |segment: &str| {
+            if prefix.is_empty() {
+                segment.to_string()
+            } else {
+                format!("{}{}{}", prefix, separator, segment)
+            }
+        };
+        match self {
+            JsonValue::Array(arr) => {
+                for (index, value) in arr.iter().enumerate() {
+                    value.collect_flat(join(&index.to_string()), separator, map);
+                }
+            }
+            JsonValue::Object(obj) => {
+                for (key, value) in obj {
+                    value.collect_flat(join(key), separator, map);
+                }
+            }
+            scalar => {
+                map.insert(prefix, scalar.coerce_string().unwrap_or_default());
+            }
+        }
+    }
+
+    // The inverse of `to_flat_map`. Every leaf comes back as a string (see
+    // `as_i64_lenient` and `as_bool_lenient`), and an object whose keys are
+    // exactly 0..n becomes an array. When a key is both a leaf and a parent,
+    // as with "a" and "a.b", the children win.
+    pub fn from_flat_map(map: &HashMap<String, String>, separator: &str) -> JsonValue {
+        let mut root = JsonValue::Object(HashMap::new());
+        let mut keys: Vec<&String> = map.keys().collect();
+        keys.sort_unstable();
+        for key in keys {
+            let mut current = &mut root;
+            for segment in key.split(separator) {
+                if !current.is_object() {
+                    *current = JsonValue::Object(HashMap::new());
+                }
+                current = match current {
+                    JsonValue::Object(obj) => obj.entry(segment.to_string()).or_insert(JsonValue::Null),
+                    _ => unreachable!(),
+                };
+            }
+            if !current.is_object() {
+                *current = JsonValue::String(map[key].clone());
+            }
+        }
+        root.objects_to_arrays();
+        root
+    }
+
+    fn objects_to_arrays(&mut self) {
+        let obj = match self {
+            JsonValue::Object(obj) => obj,
+            _ => return,
+        };
+        for value in obj.values_mut() {
+            value.objects_to_arrays();
+        }
+        let is_sequence = !obj.is_empty()
+            && (0..obj.len()).all(|index| obj.contains_key(&index.to_string()));
+        if is_sequence {
+            let arr = (0..obj.len())
+                .map(|index| obj.remove(&index.to_string()).unwrap())
+                .collect();
+            *self = JsonValue::Array(arr);
+        }
+    }
+
+    // Encodes an object as an application/x-www-form-urlencoded body, members
+    // in key order. Nested objects use brackets (`a[b]=1`) and arrays follow
+    // `arrays`; null is an empty value and empty arrays and objects are left
+    // out. Any value other than an object encodes as "".
+    pub fn to_query_string(&self, arrays: QueryArrayStyle) -> String {
+        let mut pairs = Vec::new();
+        if let JsonValue::Object(obj) = self {
+            for (key, value) in sorted_members(obj) {
+                value.collect_query_pairs(key.clone(), arrays, &mut pairs);
+            }
+        }
+        pairs
+            .iter()
+            .map(|(key, value)| format!("{}={}", form_urlencode(key), form_urlencode(value)))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    fn collect_query_pairs(&self, key: String, arrays: QueryArrayStyle, pairs: &mut Vec<(String, String)>) {
+        match self {
+            JsonValue::Array(arr) => {
+                for (index, value) in arr.iter().enumerate() {
+                    let item_key = match arrays {
+                        QueryArrayStyle::Repeat => key.clone(),
+                        QueryArrayStyle::Brackets => format!("{}[]", key),
+                        QueryArrayStyle::Indices => format!("{}[{}]", key, index),
+                    };
+                    value.collect_query_pairs(item_key, arrays, pairs);
+                }
+            }
+            JsonValue::Object(obj) => {
+                for (member, value) in sorted_members(obj) {
+                    value.collect_query_pairs(format!("{}[{}]", key, member), arrays, pairs);
+                }
+            }
+            scalar => pairs.push((key, scalar.coerce_string().unwrap_or_default())),
+        }
+    }
+
+    // Applies an RFC 6902 patch (an array of operation objects). The patch is
+    // all-or-nothing: if any operation fails, `self` is left untouched.
+    pub fn apply_patch(&mut self, patch: &JsonValue) -> Result<(), String> {
+        let operations = match patch {
+            JsonValue::Array(operations) => operations,
+            _ => return Err("Patch must be an array of operations".to_string()),
+        };
+
+        let mut document = self.clone();
+        for operation in operations {
+            document.apply_patch_operation(operation)?;
+        }
+        *self = document;
+        Ok(())
+    }
+
+    fn apply_patch_operation(&mut self, operation: &JsonValue) -> Result<(), String> {
+        let op = match operation {
+            JsonValue::Object(op) => op,
+            _ => return Err("Patch operation must be an object".to_string()),
+        };
+        let member = |name: &str| -> Result<&JsonValue, String> {
+            op.get(name)
+                .ok_or_else(|| format!("Patch operation is missing '{}'", name))
+        };
+        let pointer_member = |name: &str| -> Result<Vec<String>, String> {
+            match member(name)? {
+                JsonValue::String(pointer) => parse_pointer(pointer),
+                _ => Err(format!("Patch operation '{}' must be a string", name)),
+            }
+        };
+
+        let path = pointer_member("path")?;
+        match member("op")? {
+            JsonValue::String(name) if name == "add" => self.patch_add(&path, member("value")?.clone()),
+            JsonValue::String(name) if name == "remove" => self.patch_remove(&path).map(|_| ()),
+            JsonValue::String(name) if name == "replace" => {
+                let value = member("value")?.clone();
+                let target = self
+                    .pointer_tokens_mut(&path)
+                    .ok_or_else(|| format!("Path not found: {}", format_pointer(&path)))?;
+                *target = value;
+                Ok(())
+            }
+            JsonValue::String(name) if name == "move" => {
+                let from = pointer_member("from")?;
+                if path.len() > from.len() && path.starts_with(&from) {
+                    return Err("Cannot move a value into one of its children".to_string());
+                }
+                let value = self.patch_remove(&from)?;
+                self.patch_add(&path, value)
+            }
+            JsonValue::String(name) if name == "copy" => {
+                let from = pointer_member("from")?;
+                let value = self
+                    .pointer_tokens_mut(&from)
+                    .ok_or_else(|| format!("Path not found: {}", format_pointer(&from)))?
+                    .clone();
+                self.patch_add(&path, value)
+            }
+            JsonValue::String(name) if name == "test" => {
+                let expected = member("value")?;
+                match self.pointer_tokens_mut(&path) {
+                    Some(actual) if *actual == *expected => Ok(()),
+                    _ => Err(format!("Test failed at {}", format_pointer(&path))),
+                }
+            }
+            JsonValue::String(name) => Err(format!("Unknown patch operation '{}'", name)),
+            _ => Err("Patch operation 'op' must be a string".to_string()),
+        }
+    }
+
+    fn patch_add(&mut self, path: &[String], value: JsonValue) -> Result<(), String> {
+        let (last, parent_path) = match path.split_last() {
+            Some(split) => split,
+            None => {
+                *self = value;
+                return Ok(());
+            }
+        };
+        let parent = self
+            .pointer_tokens_mut(parent_path)
+            .ok_or_else(|| format!("Path not found: {}", format_pointer(parent_path)))?;
+
+        match parent {
+            JsonValue::Object(obj) => {
+                obj.insert(last.clone(), value);
+                Ok(())
+            }
+            JsonValue::Array(arr) => {
+                // "-" refers to the position after the last element
+                let index = if last == "-" { arr.len() } else { parse_array_index(last)? };
+                if index > arr.len() {
+                    return Err(format!("Array index out of bounds: {}", format_pointer(path)));
+                }
+                arr.insert(index, value);
+                Ok(())
+            }
+            _ => Err(format!("Cannot add to a scalar at {}", format_pointer(parent_path))),
+        }
+    }
+
+    fn patch_remove(&mut self, path: &[String]) -> Result<JsonValue, String> {
+        let (last, parent_path) = path
+            .split_last()
+            .ok_or_else(|| "Cannot remove the document root".to_string())?;
+        let removed = match self.pointer_tokens_mut(parent_path) {
+            Some(JsonValue::Object(obj)) => obj.remove(last),
+            Some(JsonValue::Array(arr)) => {
+                let index = parse_array_index(last)?;
+                if index < arr.len() {
+                    Some(arr.remove(index))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        removed.ok_or_else(|| format!("Path not found: {}", format_pointer(path)))
+    }
+
+    // Checks the value against a small subset of JSON Schema and reports every
+    // mismatch rather than stopping at the first. Understood keywords:
+    // "type" (a name or an array of names, where "integer" means a whole
+    // number), "required", "properties" and "items" (one schema for every
+    // element). Anything else in the schema is ignored.
+    pub fn validate_schema(&self, schema: &JsonValue) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        self.check_schema(schema, &mut Vec::new(), &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn check_schema(&self, schema: &JsonValue, path: &mut Vec<String>, errors: &mut Vec<ValidationError>) {
+        let mut error = |message: String| {
+            errors.push(ValidationError { pointer: format_pointer(path), message });
+        };
+        let schema = match schema {
+            JsonValue::Object(schema) => schema,
+            _ => return error("Schema must be an object".to_string()),
+        };
+
+        if let Some(expected) = schema.get("type") {
+            let names = match expected {
+                JsonValue::String(name) => vec![name.as_str()],
+                JsonValue::Array(names) => names.iter().filter_map(JsonValue::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if names.is_empty() {
+                return error("Schema 'type' must be a type name or an array of them".to_string());
+            }
+            let matches = |name: &&str| match *name {
+                "integer" => matches!(self, JsonValue::Integer(_)) || matches!(self, JsonValue::Number(n) if n.fract() == 0.0),
+                name => name == self.type_name(),
+            };
+            if !names.iter().any(matches) {
+                return error(format!("Expected {}, found {}", names.join(" or "), self.type_name()));
+            }
+        }
+
+        if let JsonValue::Object(obj) = self {
+            if let Some(JsonValue::Array(required)) = schema.get("required") {
+                for key in required.iter().filter_map(JsonValue::as_str) {
+                    if !obj.contains_key(key) {
+                        error(format!("Missing required key '{}'", key));
+                    }
+                }
+            }
+            if let Some(JsonValue::Object(properties)) = schema.get("properties") {
+                for (key, property) in sorted_members(properties) {
+                    if let Some(value) = obj.get(key) {
+                        path.push(key.clone());
+                        value.check_schema(property, path, errors);
+                        path.pop();
+                    }
+                }
+            }
+        }
+
+        if let (JsonValue::Array(arr), Some(items)) = (self, schema.get("items")) {
+            for (index, value) in arr.iter().enumerate() {
+                path.push(index.to_string());
+                value.check_schema(items, path, errors);
+                path.pop();
+            }
+        }
+    }
+}
+
+// One mismatch found by `validate_schema`; `pointer` locates the offending value
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub pointer: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}': {}", self.pointer, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl JsonValue {
+    // Returns a copy with matching object members replaced by `replacement`.
+    // A plain key ("password") matches a member with that name at any depth;
+    // a dotted key ("user.auth.token") matches that path from the root, with
+    // arrays along the way matching every element. Both ignore ASCII case.
+    pub fn redact(&self, keys: &[&str], replacement: JsonValue) -> JsonValue {
+        let mut path = Vec::new();
+        self.redact_at(keys, &replacement, &mut path)
+    }
+
+    fn redact_at<'a>(&'a self, keys: &[&str], replacement: &JsonValue, path: &mut Vec<&'a str>) -> JsonValue {
+        match self {
+            JsonValue::Array(arr) => JsonValue::Array(
+                arr.iter()
+                    .map(|value| value.redact_at(keys, replacement, path))
+                    .collect(),
+            ),
+            JsonValue::Object(obj) => {
+                let mut redacted = HashMap::new();
+                for (key, value) in obj {
+                    path.push(key);
+                    let new_value = if keys.iter().any(|pattern| redact_matches(pattern, path)) {
+                        replacement.clone()
+                    } else {
+                        value.redact_at(keys, replacement, path)
+                    };
+                    path.pop();
+                    redacted.insert(key.clone(), new_value);
+                }
+                JsonValue::Object(redacted)
+            }
+            scalar => scalar.clone(),
+        }
+    }
+
+    // Recursively removes object members whose value is null. Array nulls are
+    // positional, so they are only dropped when `drop_array_nulls` is set.
+    pub fn remove_nulls(&mut self, drop_array_nulls: bool) {
+        match self {
+            JsonValue::Array(arr) => {
+                if drop_array_nulls {
+                    arr.retain(|value| !value.is_null());
+                }
+                for value in arr {
+                    value.remove_nulls(drop_array_nulls);
+                }
+            }
+            JsonValue::Object(obj) => {
+                obj.retain(|_, value| !value.is_null());
+                for value in obj.values_mut() {
+                    value.remove_nulls(drop_array_nulls);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn without_nulls(&self, drop_array_nulls: bool) -> JsonValue {
+        let mut value = self.clone();
+        value.remove_nulls(drop_array_nulls);
+        value
+    }
+
+    // A deep copy with `f` applied to every string value (object keys excluded)
+    pub fn map_strings<F: Fn(&str) -> String>(&self, f: F) -> JsonValue {
+        self.map_strings_with(&f)
+    }
+
+    fn map_strings_with<F: Fn(&str) -> String>(&self, f: &F) -> JsonValue {
+        match self {
+            JsonValue::String(s) => JsonValue::String(f(s)),
+            JsonValue::Array(arr) => JsonValue::Array(arr.iter().map(|value| value.map_strings_with(f)).collect()),
+            JsonValue::Object(obj) => JsonValue::Object(
+                obj.iter()
+                    .map(|(key, value)| (key.clone(), value.map_strings_with(f)))
+                    .collect(),
+            ),
+            scalar => scalar.clone(),
+        }
+    }
+
+    // A deep copy with `f` applied to every object key. If two keys map to the
+    // same name, one of the members is dropped.
+    pub fn map_keys<F: Fn(&str) -> String>(&self, f: F) -> JsonValue {
+        self.map_keys_with(&f)
+    }
+
+    fn map_keys_with<F: Fn(&str) -> String>(&self, f: &F) -> JsonValue {
+        match self {
+            JsonValue::Array(arr) => JsonValue::Array(arr.iter().map(|value| value.map_keys_with(f)).collect()),
+            JsonValue::Object(obj) => JsonValue::Object(
+                obj.iter()
+                    .map(|(key, value)| (f(key), value.map_keys_with(f)))
+                    .collect(),
+            ),
+            scalar => scalar.clone(),
+        }
+    }
+
+    // Equality that treats arrays as multisets: `[1, 2, 2]` equals `[2, 1, 2]`
+    // but not `[1, 1, 2]`. Objects and scalars compare as usual.
+    pub fn deep_eq_ignoring_array_order(&self, other: &JsonValue) -> bool {
+        match (self, other) {
+            (JsonValue::Array(a), JsonValue::Array(b)) => {
+                if a.len() != b.len() {
+                    return false;
+                }
+                // The relation is an equivalence, so greedily pairing each
+                // element with any unused equal one can't miss a matching
+                let mut used = vec![false; b.len()];
+                a.iter().all(|x| {
+                    let found = b
+                        .iter()
+                        .enumerate()
+                        .position(|(i, y)| !used[i] && x.deep_eq_ignoring_array_order(y));
+                    match found {
+                        Some(i) => {
+                            used[i] = true;
+                            true
+                        }
+                        None => false,
+                    }
+                })
+            }
+            (JsonValue::Object(a), JsonValue::Object(b)) => {
+                a.len() == b.len()
+                    && a.iter().all(|(key, x)| b.get(key).is_some_and(|y| x.deep_eq_ignoring_array_order(y)))
+            }
+            _ => self == other,
+        }
+    }
+}
+
+fn redact_matches(pattern: &str, path: &[&str]) -> bool {
+    if pattern.contains('.') {
+        let segments: Vec<&str> = pattern.split('.').collect();
+        segments.len() == path.len()
+            && segments
+                .iter()
+                .zip(path)
+                .all(|(segment, key)| segment.eq_ignore_ascii_case(key))
+    } else {
+        path.last()
+            .is_some_and(|key| key.eq_ignore_ascii_case(pattern))
+    }
+}
+
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, String> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !pointer.starts_with('/') {
+        return Err(format!("Invalid JSON pointer '{}'", pointer));
+    }
+    Ok(pointer[1..]
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+enum PathStep {
+    Key(String),
+    Index(usize),
+}
+
+// Splits "a.b[0][1].c" into steps. Returns None for malformed paths such as
+// "a..b" or "a[x]".
+fn parse_dotted_path(path: &str) -> Option<Vec<PathStep>> {
+    let mut steps = Vec::new();
+    if path.is_empty() {
+        return Some(steps);
+    }
+
+    for (i, segment) in path.split('.').enumerate() {
+        let (key, mut rest) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        // Only the first segment may start with an index, as in "[0].name"
+        if key.is_empty() && (i > 0 || rest.is_empty()) {
+            return None;
+        }
+        if !key.is_empty() {
+            steps.push(PathStep::Key(key.to_string()));
+        }
+        while !rest.is_empty() {
+            let (index, tail) = rest.strip_prefix('[')?.split_once(']')?;
+            steps.push(PathStep::Index(parse_array_index(index).ok()?));
+            rest = tail;
+        }
+    }
+    Some(steps)
+}
+
+fn format_pointer(tokens: &[String]) -> String {
+    tokens
+        .iter()
+        .map(|token| format!("/{}", token.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+fn parse_array_index(token: &str) -> Result<usize, String> {
+    // Leading zeros and signs are not allowed by RFC 6901
+    let valid = !token.is_empty()
+        && token.chars().all(|c| c.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    if !valid {
+        return Err(format!("Invalid array index '{}'", token));
+    }
+    token
+        .parse()
+        .map_err(|_| format!("Invalid array index '{}'", token))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JsonParserOptions {
+    // Parse `-0` (and `-0.0`, `-0e5`, ...) as positive zero
+    pub normalize_negative_zero: bool,
+    // Also accept `+1`, `.5` and `5.`, which strict JSON rejects
+    pub lenient_numbers: bool,
+    // Fail on integer literals such as 9007199254740993 that f64 can't hold exactly
+    pub reject_imprecise_integers: bool,
+    // Decode a `\u` escape of an unpaired surrogate as U+FFFD instead of failing,
+    // to recover data from producers that split surrogate pairs
+    pub lossy_surrogates: bool,
+    // Reject `01` and `-01`; RFC 8259 allows no leading zeros
+    pub reject_leading_zeros: bool,
+    // Reject U+0000 to U+001F written raw inside strings rather than escaped
+    pub reject_control_characters: bool,
+}
+
+// Presets to start from; adjust individual fields with `..JsonParserOptions::lenient()`
+impl JsonParserOptions {
+    // RFC 8259 input only, and no silent loss of integer precision
+    pub fn strict() -> Self {
+        JsonParserOptions {
+            reject_imprecise_integers: true,
+            reject_leading_zeros: true,
+            reject_control_characters: true,
+            ..Default::default()
+        }
+    }
+
+    // Everything the parser can be talked into accepting
+    pub fn lenient() -> Self {
+        JsonParserOptions {
+            lenient_numbers: true,
+            lossy_surrogates: true,
+            ..Default::default()
+        }
+    }
+}
+
+// Where a value or key sits in the source. Offsets count characters and the
+// end is exclusive; lines and columns are 1-based, with the end pointing just
+// past the last character.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+// Spans from `parse_with_spans`, keyed by JSON Pointer. An object member's
+// pointer finds both its key (in `keys`) and its value (in `values`).
+#[derive(Debug, Clone, Default)]
+pub struct JsonSpans {
+    pub values: HashMap<String, Span>,
+    pub keys: HashMap<String, Span>,
+}
+
+// Counts from `validate_with_stats`. `values` includes every array element,
+// member value and the document itself; `max_depth` is the deepest nesting of
+// arrays and objects, so a lone scalar is 0 and `[[]]` is 2.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct JsonStats {
+    pub values: usize,
+    pub objects: usize,
+    pub arrays: usize,
+    pub keys: usize,
+    pub max_depth: usize,
+}
+
+pub struct JsonParser {
+    scanner: Scanner,
+    options: JsonParserOptions,
+    // Set by `validate` so values are checked without being kept
+    discard: bool,
+    // Reused while decoding strings with escapes so each one costs a single
+    // exact-size allocation
+    string_buffer: String,
+    // Collected only during `parse_with_warnings`
+    warnings: Option<Vec<ParseWarning>>,
+    // Collected only during `parse_with_spans`, keyed by the pointer in `span_path`
+    spans: Option<JsonSpans>,
+    span_path: Vec<String>,
+    // Collected only during `validate_with_stats`, with `nesting` the current depth
+    stats: Option<JsonStats>,
+    nesting: usize,
+}
+
+impl JsonParser {
+    pub fn new(input: &str) -> Self {
+        Self::with_options(input, JsonParserOptions::default())
+    }
+
+    pub fn with_options(input: &str, options: JsonParserOptions) -> Self {
+        Self::with_scanner(Scanner::new(input), options)
+    }
+
+    // Takes over an already collected buffer instead of re-collecting a &str
+    pub fn from_chars(input: Vec<char>) -> Self {
+        Self::with_scanner(Scanner::from_chars(input), JsonParserOptions::default())
+    }
+
+    fn with_scanner(scanner: Scanner, options: JsonParserOptions) -> Self {
+        JsonParser {
+            scanner,
+            options,
+            discard: false,
+            string_buffer: String::new(),
+            warnings: None,
+            spans: None,
+            span_path: Vec::new(),
+            stats: None,
+            nesting: 0,
+        }
+    }
+
+    // Readies the parser for another document, keeping its options and
+    // reusing its buffers
+    pub fn reset(&mut self, input: &str) {
+        self.scanner.reset(input);
+        self.discard = false;
+        self.warnings = None;
+        self.spans = None;
+        self.span_path.clear();
+        self.stats = None;
+        self.nesting = 0;
+    }
+
+    pub fn parse(&mut self) -> Result<JsonValue, ParseError> {
+        let value = self.parse_one()?;
+        self.scanner.skip_whitespace();
+        if !self.scanner.is_at_end() {
+            return Err("Unexpected characters after JSON value".into());
+        }
+        Ok(value)
+    }
+
+    pub fn validate(&mut self) -> Result<(), ParseError> {
+        self.discard = true;
+        let result = self.parse().map(|_| ());
+        self.discard = false;
+        result
+    }
+
+    // Like `validate`, counting what the document holds along the way
+    pub fn validate_with_stats(&mut self) -> Result<JsonStats, ParseError> {
+        self.stats = Some(JsonStats::default());
+        self.nesting = 0;
+        let result = self.validate();
+        let stats = self.stats.take().unwrap_or_default();
+        result.map(|_| stats)
+    }
+
+    // Like `parse`, but also returns warnings such as duplicate keys resolved
+    // by keeping the last value
+    pub fn parse_with_warnings(&mut self) -> Result<(JsonValue, Vec<ParseWarning>), ParseError> {
+        self.warnings = Some(Vec::new());
+        let result = self.parse();
+        let mut warnings = self.warnings.take().unwrap_or_default();
+        locate_warnings(&mut warnings, &self.scanner);
+        result.map(|value| (value, warnings))
+    }
+
+    // Callers check `self.warnings` first so a plain parse doesn't build messages
+    fn warn(&mut self, position: usize, message: String) {
+        if let Some(warnings) = &mut self.warnings {
+            warnings.push(ParseWarning { message, position, line: 0, column: 0 });
+        }
+    }
+
+    // Like `parse`, but also returns the source span of every value and
+    // object key, for editor tooling
+    pub fn parse_with_spans(&mut self) -> Result<(JsonValue, JsonSpans), ParseError> {
+        self.spans = Some(JsonSpans::default());
+        self.span_path.clear();
+        let result = self.parse();
+        let mut spans = self.spans.take().unwrap_or_default();
+        let value = result?;
+
+        // Lines are found once here rather than by rescanning for every span
+        let line_starts = self.scanner.line_starts();
+        for span in spans.values.values_mut().chain(spans.keys.values_mut()) {
+            (span.start_line, span.start_column) = line_starts.line_column(span.start);
+            (span.end_line, span.end_column) = line_starts.line_column(span.end);
+        }
+        Ok((value, spans))
+    }
+
+    fn record_span(&mut self, start: usize, end: usize, is_key: bool) {
+        if let Some(spans) = &mut self.spans {
+            let span = Span { start, end, start_line: 0, start_column: 0, end_line: 0, end_column: 0 };
+            let map = if is_key { &mut spans.keys } else { &mut spans.values };
+            map.insert(format_pointer(&self.span_path), span);
+        }
+    }
+
+    // Character offset reached so far; after an error, where parsing stopped
+    pub fn position(&self) -> usize {
+        self.scanner.position()
+    }
+
+    // Parses the next value and leaves whatever follows it unread
+    pub fn parse_one(&mut self) -> Result<JsonValue, ParseError> {
+        self.parse_value()
+    }
+
+    // Parses a top-level array, skipping malformed elements instead of failing.
+    // Returns the good elements and one error per skipped element; errors outside
+    // the elements (a missing `[`, unterminated input) still fail the whole parse.
+    pub fn parse_array_lenient(&mut self) -> Result<(Vec<JsonValue>, Vec<ParseError>), ParseError> {
+        let mut values = Vec::new();
+        let mut errors = Vec::new();
+
+        self.scanner.skip_whitespace();
+        match self.scanner.peek_char() {
+            Some('[') => {
+                self.scanner.next_char();
+            }
+            Some(c) => return Err(self.unexpected_char("'['", c)),
+            None => return Err(ParseError::UnexpectedEof { expected: "'['" }),
+        }
+
+        self.scanner.skip_whitespace();
+        if self.scanner.peek_char() == Some(']') {
+            self.scanner.next_char();
+        } else {
+            loop {
+                let start = self.scanner.position();
+                match self.parse_value() {
+                    Ok(value) => values.push(value),
+                    Err(err) => {
+                        errors.push(err);
+                        self.scanner.set_position(start);
+                        self.skip_to_delimiter();
+                    }
+                }
+
+                self.scanner.skip_whitespace();
+                match self.scanner.peek_char() {
+                    Some(',') => {
+                        self.scanner.next_char();
+                    }
+                    Some(']') => {
+                        self.scanner.next_char();
+                        break;
+                    }
+                    // Junk after a good value, as in `[1 2, 3]`
+                    Some(c) => {
+                        errors.push(self.unexpected_char("',' or ']'", c));
+                        values.pop();
+                        self.skip_to_delimiter();
+                        if self.scanner.next_char() == Some(']') {
+                            break;
+                        }
+                    }
+                    None => return Err(ParseError::UnexpectedEof { expected: "',' or ']'" }),
+                }
+            }
+        }
+
+        self.scanner.skip_whitespace();
+        if !self.scanner.is_at_end() {
+            return Err("Unexpected characters after JSON value".into());
+        }
+        Ok((values, errors))
+    }
+
+    // Advances to the next `,` or `]` that isn't nested in a string, array or object
+    fn skip_to_delimiter(&mut self) {
+        let mut depth = 0usize;
+        let mut in_string = false;
+        while let Some(c) = self.scanner.peek_char() {
+            if in_string {
+                match c {
+                    '\\' => {
+                        self.scanner.next_char();
+                    }
+                    '"' => in_string = false,
+                    _ => {}
+                }
+            } else {
+                match c {
+                    '"' => in_string = true,
+                    '[' | '{' => depth += 1,
+                    ']' | '}' if depth > 0 => depth -= 1,
+                    ',' | ']' if depth == 0 => return,
+                    _ => {}
+                }
+            }
+            self.scanner.next_char();
+        }
+    }
+
+    // Yields successive top-level values (NDJSON or back-to-back documents)
+    pub fn values(&mut self) -> JsonValues<'_> {
+        JsonValues {
+            parser: self,
+            failed: false,
+        }
+    }
+
+    fn parse_null(&mut self) -> Result<JsonValue, ParseError> {
+        if self.consume_keyword("null")? {
+            Ok(JsonValue::Null)
+        } else {
+            Err("Expected null".into())
+        }
+    }
+
+    fn parse_boolean(&mut self) -> Result<JsonValue, ParseError> {
+        if self.consume_keyword("true")? {
+            Ok(JsonValue::Boolean(true))
+        } else if self.consume_keyword("false")? {
+            Ok(JsonValue::Boolean(false))
+        } else {
+            Err("Expected true or false".into())
+        }
+    }
+
+    // Consumes `keyword` if the input continues with it. Input that stops
+    // part-way through the keyword is reported as end of input.
+    fn consume_keyword(&mut self, keyword: &'static str) -> Result<bool, ParseError> {
+        if self.scanner.consume_literal(keyword) {
+            return Ok(true);
+        }
+
+        let rest = self.scanner.remaining();
+        let truncated = rest.len() < keyword.len() && keyword.chars().zip(rest).all(|(a, &b)| a == b);
+        if truncated {
+            Err(ParseError::UnexpectedEof { expected: keyword })
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.scanner.next_char(); // Skip opening quote
+
+        // Without escapes the string is copied straight out of the input into
+        // a single allocation of the right size
+        let reject_control = self.options.reject_control_characters;
+        let rest = self.scanner.remaining();
+        if let Some(end) = rest.iter().position(|&c| c == '"' || c == '\\' || (reject_control && c < '\u{20}')) {
+            if rest[end] == '"' {
+                let mut string = String::new();
+                if !self.discard {
+                    string.reserve_exact(rest[..end].iter().map(|c| c.len_utf8()).sum());
+                    string.extend(&rest[..end]);
+                }
+                self.scanner.set_position(self.scanner.position() + end + 1);
+                return Ok(string);
+            }
+        }
+
+        self.string_buffer.clear();
+        
+        while let Some(c) = self.scanner.next_char() {
+            let decoded = match c {
+                '"' => return Ok(self.string_buffer.as_str().to_owned()),
+                '\\' => {
+                    match self.scanner.next_char() {
+                        Some(next @ ('"' | '\\' | '/')) => next,
+                        Some('b') => '\x08',
+                        Some('f') => '\x0c',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.parse_unicode_escape()?,
+                        Some(_) => return Err("Invalid escape sequence".into()),
+                        None => break,
+                    }
+                }
+                c if c < '\u{20}' && self.options.reject_control_characters => {
+                    return Err(format!("Unescaped control character U+{:04X} in string", c as u32).into());
+                }
+                _ => c,
+            };
+            if !self.discard {
+                self.string_buffer.push(decoded);
+            }
+        }
+        
+        Err(ParseError::UnexpectedEof { expected: "closing quote of string" })
+    }
+
+    // The char of a `\uXXXX` escape, after the `u`. Outside the BMP it is a
+    // UTF-16 surrogate pair: a high surrogate escape then a low one.
+    fn parse_unicode_escape(&mut self) -> Result<char, ParseError> {
+        let code = match self.parse_hex_digits()? {
+            high @ 0xD800..=0xDBFF => {
+                let after_high = self.scanner.position();
+                let low = self.scanner.consume_literal("\\u").then(|| self.parse_hex_digits());
+                match low {
+                    Some(Ok(low @ 0xDC00..=0xDFFF)) => 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00),
+                    // Whatever followed is read again as ordinary string content
+                    _ if self.options.lossy_surrogates => {
+                        self.scanner.set_position(after_high);
+                        char::REPLACEMENT_CHARACTER as u32
+                    }
+                    Some(Err(err)) => return Err(err),
+                    None if matches!(self.scanner.remaining(), [] | ['\\']) => {
+                        return Err(ParseError::UnexpectedEof { expected: "low surrogate" });
+                    }
+                    _ => return Err("Unpaired high surrogate in unicode escape".into()),
+                }
+            }
+            0xDC00..=0xDFFF if self.options.lossy_surrogates => char::REPLACEMENT_CHARACTER as u32,
+            0xDC00..=0xDFFF => return Err("Unpaired low surrogate in unicode escape".into()),
+            code => code,
+        };
+        char::from_u32(code).ok_or_else(|| "Invalid unicode escape".into())
+    }
+
+    fn parse_hex_digits(&mut self) -> Result<u32, ParseError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let c = self.scanner.next_char().ok_or(ParseError::UnexpectedEof { expected: "hex digit" })?;
+            code = code * 16 + c.to_digit(16).ok_or("Invalid unicode escape")?;
+        }
+        Ok(code)
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue, ParseError> {
+        let mut number = String::new();
+        let lenient = self.options.lenient_numbers;
+        let start = self.scanner.position();
+        let mut relaxed = false;
+        
+        match self.scanner.peek_char() {
+            Some('-') => number.push(self.scanner.next_char().unwrap()),
+            Some('+') if lenient => {
+                self.scanner.next_char();
+                relaxed = true;
+            }
+            Some('+') => return Err("Numbers cannot start with '+'".into()),
+            _ => {}
+        }
+        
+        let mut has_integer_digit = false;
+        let integer_start = number.len();
+        while let Some(c) = self.scanner.peek_char() {
+            if c.is_ascii_digit() {
+                number.push(self.scanner.next_char().unwrap());
+                has_integer_digit = true;
+            } else {
+                break;
+            }
+        }
+        if self.options.reject_leading_zeros
+            && number[integer_start..].len() > 1
+            && number[integer_start..].starts_with('0')
+        {
+            return Err("Numbers cannot have leading zeros".into());
+        }
+
+        if self.scanner.peek_char() == Some('.') {
+            if !has_integer_digit && !lenient {
+                return Err("Expected digit before decimal point".into());
+            }
+            number.push(self.scanner.next_char().unwrap());
+            // `5.` is only accepted leniently, and `.` on its own never
+            let mut has_digit = lenient && has_integer_digit;
+            let mut has_fraction_digit = false;
+
+            while let Some(c) = self.scanner.peek_char() {
+                if c.is_ascii_digit() {
+                    number.push(self.scanner.next_char().unwrap());
+                    has_digit = true;
+                    has_fraction_digit = true;
+                } else {
+                    break;
+                }
+            }
+            relaxed |= !has_integer_digit || !has_fraction_digit;
+            
+            if !has_digit {
+                return Err(self.expected_digit("Expected digit after decimal point"));
+            }
+        }
+
+        if let Some('e') | Some('E') = self.scanner.peek_char() {
+            number.push(self.scanner.next_char().unwrap());
+            
+            if let Some('+') | Some('-') = self.scanner.peek_char() {
+                number.push(self.scanner.next_char().unwrap());
             }
 
             let mut has_digit = false;
-            while let Some(c) = self.peek_char() {
-                if c.is_digit(10) {
-                    number.push(self.next_char().unwrap());
+            while let Some(c) = self.scanner.peek_char() {
+                if c.is_ascii_digit() {
+                    number.push(self.scanner.next_char().unwrap());
                     has_digit = true;
                 } else {
                     break;
@@ -402,111 +4067,265 @@ impl JsonParser {
             }
             
             if !has_digit {
-                return Err("Expected digit after exponent".to_string());
+                return Err(self.expected_digit("Expected digit after exponent"));
             }
         }
         
-        number.parse::<f64>()
-            .map(JsonValue::Number)
-            .map_err(|_| "Invalid number".to_string())
+        if number.is_empty() || number == "-" {
+            return Err(self.expected_digit("Expected digit"));
+        }
+
+        // "-0" stays a float unless the sign is being dropped anyway
+        let value = match number.parse::<i64>() {
+            Ok(n) if n != 0 || !number.starts_with('-') || self.options.normalize_negative_zero => JsonValue::Integer(n),
+            _ => JsonValue::Number(self.float_value(&number)?),
+        };
+
+        if relaxed && self.warnings.is_some() {
+            let text: String = self.scanner.consumed_since(start).iter().collect();
+            self.warn(start, format!("Number '{}' is not valid strict JSON", text));
+        }
+
+        Ok(value)
+    }
+
+    // A number literal with a fraction or exponent, or an integer past i64
+    fn float_value(&self, number: &str) -> Result<f64, ParseError> {
+        let value = number.parse::<f64>()
+            .map_err(|_| ParseError::from("Invalid number"))?;
+
+        // f64 parsing saturates to infinity instead of failing
+        if !value.is_finite() {
+            return Err("Number out of range".into());
+        }
+
+        // Every integer below 2^53 is exact; from there on, the shortest decimal
+        // that round-trips must be the literal itself
+        let is_integer = !number.contains(['.', 'e', 'E']);
+        if self.options.reject_imprecise_integers
+            && is_integer
+            && value.abs() >= 9007199254740992.0
+            && format!("{:.0}", value) != number
+        {
+            return Err("Integer exceeds safe f64 precision".into());
+        }
+
+        if value == 0.0 && self.options.normalize_negative_zero {
+            return Ok(0.0);
+        }
+
+        Ok(value)
     }
 
-    fn parse_array(&mut self) -> Result<JsonValue, String> {
-        self.next_char(); // Skip opening bracket
+    fn expected_digit(&self, message: &str) -> ParseError {
+        match self.scanner.peek_char() {
+            Some(_) => message.into(),
+            None => ParseError::UnexpectedEof { expected: "digit" },
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<JsonValue, ParseError> {
+        self.scanner.next_char(); // Skip opening bracket
         let mut array = Vec::new();
+        let mut first = true;
         
         loop {
-            self.skip_whitespace();
+            self.scanner.skip_whitespace();
             
-            if let Some(']') = self.peek_char() {
-                self.next_char();
+            if let Some(']') = self.scanner.peek_char() {
+                self.scanner.next_char();
                 return Ok(JsonValue::Array(array));
             }
             
-            if !array.is_empty() {
-                match self.peek_char() {
+            if !first {
+                match self.scanner.peek_char() {
                     Some(',') => {
-                        self.next_char();
-                        self.skip_whitespace();
+                        self.scanner.next_char();
+                        self.scanner.skip_whitespace();
                     }
-                    _ => return Err("Expected comma".to_string()),
+                    Some(c) => return Err(self.unexpected_char("',' or ']'", c)),
+                    None => return Err(ParseError::UnexpectedEof { expected: "',' or ']'" }),
                 }
             }
 
-            array.push(self.parse_value()?);
+            if self.spans.is_some() {
+                self.span_path.push(array.len().to_string());
+            }
+            let value = self.parse_value()?;
+            if self.spans.is_some() {
+                self.span_path.pop();
+            }
+            if !self.discard {
+                array.push(value);
+            }
+            first = false;
         }
     }
 
-    fn parse_object(&mut self) -> Result<JsonValue, String> {
-        self.next_char(); // Skip opening brace
+    fn parse_object(&mut self) -> Result<JsonValue, ParseError> {
+        self.scanner.next_char(); // Skip opening brace
         let mut object = HashMap::new();
+        let mut first = true;
         
         loop {
-            self.skip_whitespace();
+            self.scanner.skip_whitespace();
             
-            if let Some('}') = self.peek_char() {
-                self.next_char();
+            if let Some('}') = self.scanner.peek_char() {
+                self.scanner.next_char();
                 return Ok(JsonValue::Object(object));
             }
             
-            if !object.is_empty() {
-                match self.peek_char() {
+            if !first {
+                match self.scanner.peek_char() {
                     Some(',') => {
-                        self.next_char();
-                        self.skip_whitespace();
+                        self.scanner.next_char();
+                        self.scanner.skip_whitespace();
                     }
-                    _ => return Err("Expected comma".to_string()),
+                    Some(c) => return Err(self.unexpected_char("',' or '}'", c)),
+                    None => return Err(ParseError::UnexpectedEof { expected: "',' or '}'" }),
                 }
             }
 
-            match self.parse_value()? {
-                JsonValue::String(key) => {
-                    self.skip_whitespace();
-                    if self.next_char() != Some(':') {
-                        return Err("Expected colon".to_string());
-                    }
-                    let value = self.parse_value()?;
-                    object.insert(key, value);
+            let key_start = self.scanner.position();
+            let key = match self.scanner.peek_char() {
+                Some('"') => self.parse_string()?,
+                Some(c) => return Err(format!("Object key must be a string, found '{}'", c).into()),
+                None => return Err(ParseError::UnexpectedEof { expected: "object key" }),
+            };
+            let key_end = self.scanner.position();
+            if let Some(stats) = &mut self.stats {
+                stats.keys += 1;
+            }
+
+            self.scanner.skip_whitespace();
+            match self.scanner.peek_char() {
+                Some(':') => {
+                    self.scanner.next_char();
+                }
+                Some(c) => return Err(self.unexpected_char("':'", c)),
+                None => return Err(ParseError::UnexpectedEof { expected: "':'" }),
+            }
+            if self.spans.is_some() {
+                self.span_path.push(key.clone());
+                self.record_span(key_start, key_end, true);
+            }
+            let value = self.parse_value()?;
+            if self.spans.is_some() {
+                self.span_path.pop();
+            }
+            if !self.discard {
+                if self.warnings.is_some() && object.contains_key(&key) {
+                    self.warn(key_start, format!("Duplicate key '{}', keeping the last value", key));
                 }
-                _ => return Err("Expected string as object key".to_string()),
+                object.insert(key, value);
+            }
+            first = false;
+        }
+    }
+
+    // For a delimiter error at the current position, e.g.
+    // "line 3, column 12: expected ',' or ']', found '}'"
+    fn unexpected_char(&self, expected: &str, found: char) -> ParseError {
+        let (line, column) = self.scanner.line_column();
+        format!("line {}, column {}: expected {}, found '{}'", line, column, expected, found).into()
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, ParseError> {
+        self.scanner.skip_whitespace();
+        let start = self.scanner.position();
+        let nested = matches!(self.scanner.peek_char(), Some('[' | '{'));
+        if let Some(stats) = &mut self.stats {
+            stats.values += 1;
+            match self.scanner.peek_char() {
+                Some('[') => stats.arrays += 1,
+                Some('{') => stats.objects += 1,
+                _ => {}
             }
+            if nested {
+                self.nesting += 1;
+                stats.max_depth = stats.max_depth.max(self.nesting);
+            }
+        }
+        let value = self.parse_value_at_start()?;
+        if self.stats.is_some() && nested {
+            self.nesting -= 1;
         }
+        if self.spans.is_some() {
+            self.record_span(start, self.scanner.position(), false);
+        }
+        Ok(value)
     }
 
-    fn parse_value(&mut self) -> Result<JsonValue, String> {
-        self.skip_whitespace();
-        match self.peek_char() {
+    fn parse_value_at_start(&mut self) -> Result<JsonValue, ParseError> {
+        match self.scanner.peek_char() {
             Some('n') => self.parse_null(),
             Some('t') | Some('f') => self.parse_boolean(),
-            Some('"') => self.parse_string(),
+            Some('"') => self.parse_string().map(JsonValue::String),
             Some('[') => self.parse_array(),
             Some('{') => self.parse_object(),
-            Some(c) if c.is_digit(10) || c == '-' => self.parse_number(),
-            Some(c) => Err(format!("Unexpected character '{}'", c)),
-            None => Err("Unexpected end of input".to_string()),
+            Some(c) if c.is_ascii_digit() || matches!(c, '-' | '+' | '.') => self.parse_number(),
+            Some(c) => Err(format!("Unexpected character '{}'", c).into()),
+            None => Err(ParseError::UnexpectedEof { expected: "value" }),
         }
     }
 
-    fn skip_whitespace(&mut self) {
-        while let Some(c) = self.peek_char() {
-            if !c.is_whitespace() {
-                break;
-            }
-            self.next_char();
+}
+
+pub struct JsonValues<'a> {
+    parser: &'a mut JsonParser,
+    failed: bool,
+}
+
+impl Iterator for JsonValues<'_> {
+    type Item = Result<JsonValue, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
         }
-    }
 
-    fn peek_char(&self) -> Option<char> {
-        self.input.get(self.position).copied()
-    }
+        self.parser.scanner.skip_whitespace();
+        if self.parser.scanner.is_at_end() {
+            return None;
+        }
 
-    fn next_char(&mut self) -> Option<char> {
-        let c = self.peek_char();
-        self.position += 1;
-        c
+        let result = self.parser.parse_one();
+        // The position after an error is unreliable, so stop there
+        if result.is_err() {
+            self.failed = true;
+        }
+        Some(result)
     }
 }
 
+// The message above the source line at `position` with a caret under it
+#[cfg(feature = "pretty-errors")]
+fn render_error(source: &str, position: usize, message: &str) -> String {
+    const RED: &str = "\x1b[1;31m";
+    const BLUE: &str = "\x1b[1;34m";
+    const RESET: &str = "\x1b[0m";
+
+    let before: String = source.chars().take(position).collect();
+    let line_number = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count();
+    let line = source[line_start..].lines().next().unwrap_or("");
+
+    let gutter = " ".repeat(line_number.to_string().len());
+    format!(
+        "{red}error{reset}: {message}\n\
+         {gutter}{blue}-->{reset} line {line_number}, column {col}\n\
+         {gutter} {blue}|{reset}\n\
+         {blue}{line_number} |{reset} {line}\n\
+         {gutter} {blue}|{reset} {pad}{red}^{reset}",
+        red = RED,
+        blue = BLUE,
+        reset = RESET,
+        col = column + 1,
+        pad = " ".repeat(column),
+    )
+}
+
 fn main() {
     // Example XML
     // let args: Vec<String> = std::env::args().collect();
@@ -545,6 +4364,1005 @@ fn main() {
              let xml = json_value.to_xml();
              println!("{}", xml);
          }
+         #[cfg(feature = "pretty-errors")]
+         Err(e) => println!("{}", render_error(json_str, json_parser.position(), &e.to_string())),
+         #[cfg(not(feature = "pretty-errors"))]
          Err(e) => println!("Error parsing JSON: {}", e),
      }
- }
\ No newline at end of file
+ }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Counts allocations and live heap bytes, for the memory benchmarks below
+    struct CountingAllocator;
+
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+    static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn lenient_json() -> JsonParserOptions {
+        JsonParserOptions { lenient_numbers: true, ..JsonParserOptions::default() }
+    }
+
+    #[test]
+    fn warnings_carry_line_and_column() {
+        let mut parser = JsonParser::with_options("{\n  \"a\": +1,\n  \"a\": 2\n}", lenient_json());
+        let (_, warnings) = parser.parse_with_warnings().unwrap();
+        let located: Vec<_> = warnings.iter().map(|w| (w.line, w.column)).collect();
+        assert_eq!(located, [(2, 8), (3, 3)]);
+
+        let mut parser = XmlParser::new("<a\n x='1'\n x='2'/>");
+        let (_, warnings) = parser.parse_with_warnings().unwrap();
+        assert_eq!((warnings[0].line, warnings[0].column), (3, 2));
+    }
+
+    #[test]
+    fn plain_parse_of_many_relaxed_numbers_is_linear() {
+        let input = format!("[{}1]", "+1,".repeat(200_000));
+        let start = std::time::Instant::now();
+        JsonParser::with_options(&input, lenient_json()).parse().unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    fn parse_xml(input: &str) -> XmlNode {
+        XmlParser::new(input).parse().unwrap()
+    }
+
+    #[test]
+    fn to_json_string_keeps_first_appearance_order() {
+        let root = parse_xml("<r><b>1</b><a>2</a><b>3</b></r>");
+        assert_eq!(root.to_json_string(), r#"{"b":["1","3"],"a":"2"}"#);
+
+        let root = parse_xml("<r z='1' y='2'>t<b/><a/><b/></r>");
+        assert_eq!(
+            root.to_json_string(),
+            r##"{"@attributes":{"y":"2","z":"1"},"#text":"t","b":[null,null],"a":null}"##
+        );
+    }
+
+    #[test]
+    fn to_json_string_matches_to_json() {
+        let root = parse_xml("<r><b k='v'>1</b><a><c>2</c></a><b>3</b></r>");
+        let reparsed: Value = serde_json::from_str(&root.to_json_string()).unwrap();
+        assert_eq!(reparsed, root.to_json());
+    }
+
+    fn parse_json(input: &str) -> JsonValue {
+        JsonParser::new(input).parse().unwrap()
+    }
+
+    #[test]
+    fn integer_literals_stay_exact() {
+        assert_eq!(parse_json("9007199254740993").as_i64(), Some(9007199254740993));
+        assert_eq!(parse_json("-12").as_i64(), Some(-12));
+        assert!(matches!(parse_json("1.0"), JsonValue::Number(_)));
+        assert!(matches!(parse_json("1e3"), JsonValue::Number(_)));
+        assert!(matches!(parse_json("9223372036854775808"), JsonValue::Number(_)));
+        assert_eq!(parse_json("[1234567890123456789]").to_xml(), "<root>  <item>1234567890123456789</item></root>");
+    }
+
+    #[test]
+    fn integers_equal_floats_with_the_same_value() {
+        assert_eq!(JsonValue::Integer(1), JsonValue::Number(1.0));
+        assert_ne!(JsonValue::Integer(9007199254740993), JsonValue::Number(9007199254740992.0));
+        assert!(parse_json("[1]").deep_eq_ignoring_array_order(&parse_json("[1.0]")));
+
+        let mut doc = parse_json(r#"{"a":1}"#);
+        let patch = parse_json(r#"[{"op":"test","path":"/a","value":1.0}]"#);
+        assert_eq!(doc.apply_patch(&patch), Ok(()));
+
+        // Written as digits, this float would read back as the Integer 742290761071690200
+        let large = JsonValue::Number(7.422907610716902e17);
+        assert_eq!(large.to_string(), "7.422907610716902e17");
+        assert_eq!(parse_json(&large.to_string()), large);
+        assert_eq!(JsonValue::Number(1e300).to_string(), "1e300");
+        assert_eq!(JsonValue::Number(-4096.0).to_string(), "-4096");
+    }
+
+    #[test]
+    fn xml_nodes_can_cross_threads() {
+        let root = parse_xml("<r><a k='v'/></r>");
+        let tag = std::thread::spawn(move || root.children()[0].tag().to_string()).join().unwrap();
+        assert_eq!(tag, "a");
+    }
+
+    #[test]
+    fn interned_names_are_shared() {
+        let options = XmlParserOptions { intern_names: true, ..XmlParserOptions::default() };
+        let root = XmlParser::with_options("<r><item id='1'/><item id='2'/></r>", options).parse().unwrap();
+        let [first, second] = root.children() else { panic!("expected two children") };
+        assert!(Arc::ptr_eq(&first.tag, &second.tag));
+    }
+
+    // Run alone, as the byte count is shared with any test running alongside:
+    // cargo test --release -- --ignored --nocapture intern_names_memory
+    #[test]
+    #[ignore]
+    fn intern_names_memory() {
+        let book = "<book category='fiction' language='en'><title>T</title></book>";
+        let input = format!("<catalog>{}</catalog>", book.repeat(20_000));
+        let live_after_parse = |intern_names| {
+            let before = LIVE_BYTES.load(Ordering::Relaxed);
+            let options = XmlParserOptions { intern_names, ..XmlParserOptions::default() };
+            let root = XmlParser::with_options(&input, options).parse().unwrap();
+            let bytes = LIVE_BYTES.load(Ordering::Relaxed) - before;
+            drop(root);
+            bytes
+        };
+        let plain = live_after_parse(false);
+        let interned = live_after_parse(true);
+        let saved = 100.0 * (1.0 - interned as f64 / plain as f64);
+        println!("plain: {} bytes, interned: {} bytes ({:.1}% less)", plain, interned, saved);
+        assert!(interned < plain);
+    }
+
+    // cargo test --release -- --ignored --nocapture string_allocations
+    #[test]
+    #[ignore]
+    fn string_allocations() {
+        let record = r#"{"identifier": "3f9c2a1b-77d0", "displayName": "Some Display Name",
+            "emailAddress": "someone@example.com", "tags": ["first tag", "second tag"]}"#;
+        let input = format!("[{}]", vec![record; 20_000].join(","));
+        let strings = 20_000 * 9;
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let value = JsonParser::new(&input).parse().unwrap();
+        let parsed = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        drop(value);
+
+        // What decoding into a fresh, growing String per token would cost
+        let tokens: Vec<&str> = input.split('"').skip(1).step_by(2).collect();
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let grown: Vec<String> = tokens
+            .iter()
+            .map(|token| {
+                let mut string = String::new();
+                token.chars().for_each(|c| string.push(c));
+                string
+            })
+            .collect();
+        let growing = ALLOCATIONS.load(Ordering::Relaxed) - before - 1;
+        drop(grown);
+
+        println!(
+            "{} strings: {} allocations for the whole parse, {} for growing strings alone",
+            strings, parsed, growing
+        );
+        assert_eq!(tokens.len(), strings);
+        assert!(parsed - strings < strings / 2);
+        assert!(parsed < growing);
+    }
+
+    // cargo test --release -- --ignored --nocapture json_parser_throughput
+    #[test]
+    #[ignore]
+    fn json_parser_throughput() {
+        // serde_json stops at 128 levels, so each value nests 100 deep
+        let deep = format!("{}1{}", "[{\"a\": ".repeat(50), "}]".repeat(50));
+        let nested = format!("[{}]", vec![deep.as_str(); 2_000].join(","));
+        let strings = format!(
+            "[{}]",
+            vec![r#"{"key": "some text value", "other key": "caf\u00e9 \"quoted\" \\ path"}"#; 20_000].join(",")
+        );
+        let numbers = format!(
+            "[{}]",
+            (0..100_000).map(|i| format!("{}, {}.25e-3", i * 7919, i)).collect::<Vec<_>>().join(",")
+        );
+
+        for (name, input) in [("nested", &nested), ("string-heavy", &strings), ("number-heavy", &numbers)] {
+            let megabytes = input.len() as f64 / 1_000_000.0;
+            let time = |parse: &dyn Fn()| {
+                let start = std::time::Instant::now();
+                for _ in 0..5 {
+                    parse();
+                }
+                start.elapsed().as_secs_f64() / 5.0
+            };
+            let ours = time(&|| {
+                JsonParser::new(input).parse().unwrap();
+            });
+            let serde = time(&|| {
+                serde_json::from_str::<Value>(input).unwrap();
+            });
+            println!(
+                "{:>12}: JsonParser {:7.1} MB/s, serde_json {:7.1} MB/s, {:.1}x serde_json's time",
+                name,
+                megabytes / ours,
+                megabytes / serde,
+                ours / serde
+            );
+        }
+    }
+
+    #[test]
+    fn empty_element_style_picks_the_empty_tag_form() {
+        let root = parse_xml("<r><a k='v'/><b>t</b></r>");
+        assert_eq!(root.to_xml_string(), r#"<r><a k="v"/><b>t</b></r>"#);
+        let options = WriteXmlOptions {
+            empty_element_style: EmptyElementStyle::ExplicitClose,
+            ..WriteXmlOptions::default()
+        };
+        assert_eq!(root.to_xml_string_with_options(&options), r#"<r><a k="v"></a><b>t</b></r>"#);
+        assert_eq!(root.to_xml_pretty_with_options(2, &options), "<r>\n  <a k=\"v\"></a>\n  <b>t</b>\n</r>");
+    }
+
+    #[test]
+    fn reformat_xml_reindents() {
+        let input = "<?xml version='1.0'?><r  b='2' a=\"1\"><br/><p>x &amp; y</p><q><s /></q></r>";
+        let expected = "<r a=\"1\" b=\"2\">\n    <br/>\n    <p>x &amp; y</p>\n    <q>\n        <s/>\n    </q>\n</r>\n";
+        assert_eq!(reformat_xml(input, 4).unwrap(), expected);
+        assert!(reformat_xml("<r><a></r>", 2).is_err());
+    }
+
+
+    #[test]
+    fn unpaired_surrogates_fail_unless_lossy() {
+        for bad in [r#""\ud83d""#, r#""\ud83dx""#, r#""\ud83dA""#] {
+            assert_eq!(JsonParser::new(bad).parse(), Err("Unpaired high surrogate in unicode escape".into()));
+        }
+        assert_eq!(JsonParser::new(r#""\ude00""#).parse(), Err("Unpaired low surrogate in unicode escape".into()));
+        assert!(matches!(JsonParser::new(r#""\ud83d"#).parse(), Err(ParseError::UnexpectedEof { .. })));
+
+        let lossy = JsonParserOptions { lossy_surrogates: true, ..JsonParserOptions::default() };
+        let parse_lossy = |input| JsonParser::with_options(input, lossy.clone()).parse().unwrap();
+        assert_eq!(parse_lossy(r#""a\ud83db""#), JsonValue::String("a\u{FFFD}b".into()));
+        assert_eq!(parse_lossy(r#""\ude00\ud83dA""#), JsonValue::String("\u{FFFD}\u{FFFD}A".into()));
+        assert_eq!(parse_lossy(r#""\ud83d\ud83d\ude00""#), JsonValue::String("\u{FFFD}\u{1F600}".into()));
+        assert_eq!(parse_lossy(r#"{"\udc00": 1}"#), parse_json(r#"{"\ufffd": 1}"#));
+    }
+
+    #[test]
+    fn unicode_escapes_decode() {
+        assert_eq!(parse_json(r#""\u00e9A""#), JsonValue::String("\u{e9}A".into()));
+        for bad in [r#""\u00g9""#, r#""\u12""#] {
+            assert_eq!(JsonParser::new(bad).parse(), Err("Invalid unicode escape".into()));
+        }
+        assert!(matches!(JsonParser::new(r#""\u12"#).parse(), Err(ParseError::UnexpectedEof { .. })));
+    }
+
+    #[test]
+    fn surrogate_pairs_combine() {
+        assert_eq!(parse_json(r#""\ud83d\ude00!""#), JsonValue::String("\u{1F600}!".into()));
+        assert_eq!(parse_json(r#""\uD83D\uDE00""#), JsonValue::String("\u{1F600}".into()));
+        assert_eq!(JsonParser::new(r#""\ud83dA""#).parse(), Err("Unpaired high surrogate in unicode escape".into()));
+    }
+
+    #[test]
+    fn suffixed_duplicates_skip_keys_of_real_siblings() {
+        let options = ToJsonOptions { duplicate_keys: DuplicateKeyStrategy::Suffix, ..ToJsonOptions::default() };
+        let root = parse_xml("<r><i>1</i><i>2</i><i_2>X</i_2><i>3</i></r>");
+        assert_eq!(root.to_json_string_with_options(&options), r#"{"i":"1","i_3":"2","i_4":"3","i_2":"X"}"#);
+    }
+
+    #[test]
+    fn raw_elements_close_in_any_case_under_lowercase_names() {
+        let options = XmlParserOptions {
+            lowercase_names: true,
+            raw_elements: HashSet::from(["script".to_string()]),
+            ..XmlParserOptions::default()
+        };
+        let input = "<HTML><SCRIPT>if (a<b) { x = '<Script>'; }</Script>y</SCRIPT ></HTML>";
+        let root = XmlParser::with_options(input, options).parse().unwrap();
+        assert_eq!(root.children()[0].tag(), "script");
+        assert_eq!(root.children()[0].text(), Some("if (a<b) { x = '<Script>'; }</Script>y"));
+    }
+
+    #[test]
+    fn strict_json_rejects_what_rfc_8259_rejects() {
+        let strict = |input: &str| JsonParser::with_options(input, JsonParserOptions::strict()).parse();
+        assert_eq!(strict("01"), Err("Numbers cannot have leading zeros".into()));
+        assert_eq!(strict("-01"), Err("Numbers cannot have leading zeros".into()));
+        assert_eq!(strict("\"a\u{1}\""), Err("Unescaped control character U+0001 in string".into()));
+        assert_eq!(strict("[0, -0.5, 10]"), Ok(parse_json("[0, -0.5, 10]")));
+        assert_eq!(JsonParser::new("01").parse(), Ok(JsonValue::Integer(1)));
+    }
+
+    #[test]
+    fn strict_xml_rejects_what_xml_1_0_rejects() {
+        let strict = |input: &str| XmlParser::with_options(input, XmlParserOptions::strict()).parse();
+        assert_eq!(strict("<a>&#1;</a>").unwrap_err().to_string(), "Invalid character reference '&#1;' at line 1, column 8");
+        let error = strict("<a b='x<y'/>").unwrap_err();
+        assert_eq!(error.to_string(), "'<' is not allowed in attribute values at line 1, column 8");
+        assert!(strict("<a b='&lt;'>&#9;&#x10FFFF;</a>").is_ok());
+        assert!(XmlParser::new("<a b='x<y'>&#1;</a>").parse().is_ok());
+    }
+
+    fn xml_error(input: &str, options: XmlParserOptions) -> XmlError {
+        XmlParser::with_options(input, options).parse().unwrap_err()
+    }
+
+    #[test]
+    fn xml_errors_have_structured_variants() {
+        let default = XmlParserOptions::default;
+        let error = xml_error("<a>\n  <b>\n  </c>\n</a>", default());
+        assert_eq!(error.to_string(), "Mismatched tags: b and c at line 3, column 3");
+        assert!(matches!(error, XmlError::MismatchedTag { ref expected, ref found, .. } if expected == "b" && found == "c"));
+
+        let error = xml_error("<a b=x/>", default());
+        assert!(matches!(error, XmlError::UnexpectedChar { found: 'x', position: Position { column: 6, .. }, .. }));
+        assert_eq!(error.to_string(), "Expected quoted value for attribute 'b', found 'x' at line 1, column 6");
+
+        assert!(matches!(xml_error("<a>\u{1}</a>", XmlParserOptions::strict()), XmlError::InvalidChar { found: '\u{1}', .. }));
+        let error = xml_error("<a>&foo;</a>", default());
+        assert!(matches!(error, XmlError::UnknownEntity { ref name, position: Position { line: 1, .. } } if name == "foo"));
+        let error = xml_error("<!DOCTYPE a SYSTEM 'a.dtd'><a/>", default());
+        assert!(matches!(error, XmlError::ExternalEntity { .. }));
+
+        let limited = XmlParserOptions { max_attributes: 1, ..default() };
+        let error = xml_error("<a x='1' y='2'/>", limited);
+        assert!(matches!(error, XmlError::LimitExceeded { limit: XmlLimit::Attributes, max: 1, .. }));
+        assert_eq!(error.to_string(), "Element has more than the maximum of 1 attributes at line 1, column 10");
+    }
+
+    #[test]
+    fn truncated_xml_is_unexpected_eof() {
+        let error = xml_error("<a b='1", XmlParserOptions::default());
+        assert_eq!(error, XmlError::UnexpectedEof {
+            expected: "closing quote of attribute value",
+            inside: Some(XmlConstruct::String),
+        });
+        assert_eq!(error.to_string(), "Unterminated string");
+        for input in ["<a", "<a>", "<a></a", "<a b"] {
+            let error = xml_error(input, XmlParserOptions::default());
+            assert!(matches!(error, XmlError::UnexpectedEof { inside: None, .. }), "{}: {:?}", input, error);
+        }
+    }
+
+    #[test]
+    fn truncated_cdata_and_entity_references_are_unexpected_eof() {
+        let error = xml_error("<a><![CDATA[x <y>", XmlParserOptions::default());
+        assert_eq!(error, XmlError::UnexpectedEof { expected: "']]>'", inside: Some(XmlConstruct::Cdata) });
+        assert_eq!(error.to_string(), "Unterminated CDATA section");
+
+        let error = xml_error("<a>&am", XmlParserOptions::default());
+        assert_eq!(error, XmlError::UnexpectedEof { expected: "';'", inside: Some(XmlConstruct::EntityReference) });
+        assert_eq!(error.to_string(), "Unterminated entity reference");
+        let error = xml_error("<a>&am</a>", XmlParserOptions::default());
+        assert!(matches!(error, XmlError::Syntax { .. }));
+    }
+
+    #[test]
+    fn cdata_is_kept_verbatim_and_joins_adjacent_text() {
+        let root = parse_xml("<a>x &amp; <![CDATA[<b>&amp;</b> ]]]]> y</a>");
+        assert_eq!(root.text(), Some("x & <b>&amp;</b> ]] y"));
+        let root = parse_xml("<a><![CDATA[  ]]></a>");
+        assert_eq!(root.text(), Some("  "));
+    }
+
+    #[test]
+    fn comments_and_processing_instructions_are_skipped() {
+        let root = parse_xml("<!-- lead --><?pi x?><a><!-- <b/> --><b/><?pi?></a><!-- tail -->");
+        assert_eq!(root.children().len(), 1);
+
+        let error = xml_error("<a><!-- x", XmlParserOptions::default());
+        assert_eq!(error, XmlError::UnexpectedEof { expected: "'-->'", inside: Some(XmlConstruct::Comment) });
+        assert_eq!(error.to_string(), "Unterminated comment");
+        let error = xml_error("<?pi x", XmlParserOptions::default());
+        assert_eq!(error.to_string(), "Unterminated processing instruction");
+        assert!(matches!(error, XmlError::UnexpectedEof { inside: Some(XmlConstruct::ProcessingInstruction), .. }));
+    }
+
+    #[test]
+    fn every_truncated_document_is_unexpected_eof() {
+        let document = "<?xml version='1.0'?><!DOCTYPE a [<!ENTITY e 'v'><!ELEMENT a ANY>]>\
+            <a x='1'><![CDATA[c]]><!--c--><?p?>&e;&#65;<b/></a>";
+        for end in 0..document.len() {
+            let error = XmlParser::new(&document[..end]).parse().unwrap_err();
+            assert!(matches!(error, XmlError::UnexpectedEof { .. }), "{:?}: {}", &document[..end], error);
+        }
+    }
+
+    #[test]
+    fn names_follow_the_xml_name_productions() {
+        assert_eq!(parse_xml("<caf\u{e9}/>").tag(), "caf\u{e9}");
+        assert_eq!(parse_xml("<\u{65e5}\u{672c} _x.y-z='1'/>").attribute("_x.y-z"), Some("1"));
+        assert_eq!(parse_xml("<a><b\u{b7}2/></a>").children()[0].tag(), "b\u{b7}2");
+
+        let default = XmlParserOptions::default;
+        assert_eq!(xml_error("<1bad/>", default()).to_string(), "Name cannot start with '1' at line 1, column 2");
+        assert!(matches!(xml_error("<-a/>", default()), XmlError::Syntax { .. }));
+        assert!(matches!(xml_error("<a \u{b7}x='1'/>", default()), XmlError::Syntax { .. }));
+    }
+
+    #[test]
+    fn text_containing_cdata_end_reparses() {
+        let text = "a]]>b ]]]>c";
+        let mut node = XmlNode::new("a".to_string());
+        node.set_text(Some(text.to_string()));
+        let xml = node.to_xml_string();
+        assert_eq!(xml, "<a>a]]&gt;b ]]]&gt;c</a>");
+        assert_eq!(parse_xml(&xml).text(), Some(text));
+
+        let value = JsonValue::String(text.to_string());
+        assert_eq!(parse_xml(&value.to_xml()).text(), Some(text));
+        let options = ToXmlOptions { cdata_for_markup: true, ..ToXmlOptions::default() };
+        let xml = value.to_xml_with_options(&options);
+        assert_eq!(xml, "<root><![CDATA[a]]]]><![CDATA[>b ]]]]]><![CDATA[>c]]></root>");
+        assert_eq!(parse_xml(&xml).text(), Some(text));
+    }
+
+    #[test]
+    fn self_closing_tags_allow_whitespace_around_the_slash() {
+        for input in ["<br/>", "<br />", "<br/ >", "<br x='1' / \n>"] {
+            let root = parse_xml(input);
+            assert_eq!((root.tag(), root.children().len(), root.text()), ("br", 0, None), "{}", input);
+        }
+        assert_eq!(parse_xml("<p>a/b<br/ >c</p>").children()[0].tag(), "br");
+
+        let error = xml_error("<br/ x>", XmlParserOptions::default());
+        assert!(matches!(error, XmlError::UnexpectedChar { found: 'x', .. }), "{:?}", error);
+    }
+
+    #[test]
+    fn same_named_elements_nest_and_close_per_level() {
+        let root = parse_xml("<item><item/><item><item>x</item ></item><item /></item>");
+        assert_eq!(root.children().len(), 3);
+        assert_eq!(root.children()[1].children()[0].text(), Some("x"));
+        assert!(root.children()[2].children().is_empty());
+
+        let error = xml_error("<item><item/></item></item>", XmlParserOptions::default());
+        assert!(matches!(error, XmlError::Syntax { .. }), "{:?}", error);
+        let error = xml_error("<item><item></item>", XmlParserOptions::default());
+        assert!(matches!(error, XmlError::UnexpectedEof { .. }), "{:?}", error);
+    }
+
+    #[test]
+    fn lenient_numbers_accept_relaxed_forms_that_strict_rejects() {
+        for (input, expected) in [("+1", 1.0), (".5", 0.5), ("5.", 5.0), ("-.5", -0.5), ("+2.5e1", 25.0)] {
+            let value = JsonParser::with_options(input, lenient_json()).parse().unwrap();
+            assert_eq!(value.as_f64(), Some(expected), "{}", input);
+        }
+        let strict_errors = [
+            ("+1", "Numbers cannot start with '+'"),
+            (".5", "Expected digit before decimal point"),
+            ("-.5", "Expected digit before decimal point"),
+            ("5.", "Unexpected end of input, expected digit"),
+        ];
+        for (input, message) in strict_errors {
+            assert_eq!(JsonParser::new(input).parse().unwrap_err().to_string(), message);
+        }
+        // A sign or point still needs a digit somewhere
+        for input in ["+", ".", "+."] {
+            let error = JsonParser::with_options(input, lenient_json()).parse().unwrap_err();
+            assert_eq!(error, ParseError::UnexpectedEof { expected: "digit" });
+        }
+    }
+
+    #[test]
+    fn external_entities_and_dtds_are_rejected() {
+        let external = [
+            "<!DOCTYPE a SYSTEM 'http://example.com/a.dtd'><a/>",
+            "<!DOCTYPE a PUBLIC '-//A//EN' 'a.dtd'><a/>",
+            "<!DOCTYPE a [<!ENTITY x SYSTEM 'file:///etc/passwd'>]><a>&x;</a>",
+            "<!DOCTYPE a [<!ENTITY x PUBLIC '-//X//EN' 'x.xml'>]><a>&x;</a>",
+            "<!DOCTYPE a [<!ENTITY % p SYSTEM 'p.dtd'> %p;]><a/>",
+        ];
+        for input in external {
+            let error = xml_error(input, XmlParserOptions::lenient());
+            assert!(matches!(error, XmlError::ExternalEntity { .. }), "{}: {:?}", input, error);
+            assert!(error.to_string().starts_with("External entities are not supported"));
+        }
+
+        let error = xml_error("<!DOCTYPE a [<!ENTITY % p 'x'>]><a/>", XmlParserOptions::default());
+        assert_eq!(error.to_string(), "Parameter entities are not supported at line 1, column 27");
+        let error = xml_error("<!DOCTYPE a [ %p; ]><a/>", XmlParserOptions::default());
+        assert!(error.to_string().starts_with("Parameter entity references are not supported"));
+    }
+
+    #[test]
+    fn attribute_and_text_members_of_other_shapes_are_child_elements() {
+        let options = ToXmlOptions { attribute_pairs: true, text_member: true, ..ToXmlOptions::default() };
+        let value = parse_json(r##"{"a": {"@attributes": {"id": 7, "on": true}, "#text": "x"}}"##);
+        assert_eq!(value.try_to_xml_with_options(&options).unwrap(), "<root>  <a id=\"7\" on=\"true\">x</a></root>");
+        let value = parse_json(r##"{"a": {"@attributes": [["id", "7"]], "#text": 2}}"##);
+        assert_eq!(value.try_to_xml_with_options(&options).unwrap(), "<root>  <a id=\"7\">2</a></root>");
+
+        let malformed = [
+            (r##"{"a": {"@attributes": [1, 2]}}"##, "/a/@attributes"),
+            (r##"{"a": {"@attributes": {"id": {"x": 1}}}}"##, "/a/@attributes"),
+            (r##"{"a": {"#text": {"b": 1}}}"##, "/a/#text"),
+            (r##"{"a": {"#text": null}}"##, "/a/#text"),
+        ];
+        for (input, expected) in malformed {
+            let value = parse_json(input);
+            assert!(value.to_xml_with_options(&options).contains(&format!("<{}", &expected[3..])));
+            match value.try_to_xml_with_options(&options) {
+                Err(ToXmlError::InvalidName { pointer, .. }) => assert_eq!(pointer, expected),
+                other => panic!("{}: {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn values_yields_each_concatenated_document() {
+        let mut parser = JsonParser::new("1\n{\"a\": [2]}\r\n\n\"x\"true[3][]  ");
+        let values: Vec<_> = parser.values().collect::<Result<_, _>>().unwrap();
+        let expected = ["1", "{\"a\": [2]}", "\"x\"", "true", "[3]", "[]"].map(parse_json);
+        assert_eq!(values, expected);
+
+        let mut parser = JsonParser::new("{\"a\": 1}\n{\"a\" 2}\n{\"a\": 3}");
+        let results: Vec<_> = parser.values().collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok() && results[1].is_err());
+
+        assert_eq!(JsonParser::new(" \n ").values().count(), 0);
+        let mut parser = JsonParser::new("[1] tail");
+        assert_eq!(parser.parse_one().unwrap(), parse_json("[1]"));
+        assert_eq!(parser.position(), 3);
+    }
+
+    #[test]
+    fn apply_patch_runs_every_operation() {
+        let mut document = parse_json(r#"{"a": {"b": [1, 2]}, "c": "x"}"#);
+        let patch = parse_json(
+            r#"[
+                {"op": "test", "path": "/c", "value": "x"},
+                {"op": "add", "path": "/a/b/-", "value": 3},
+                {"op": "add", "path": "/a/b/0", "value": 0},
+                {"op": "remove", "path": "/a/b/1"},
+                {"op": "replace", "path": "/c", "value": {"d": null}},
+                {"op": "move", "from": "/c/d", "path": "/e"},
+                {"op": "copy", "from": "/a/b", "path": "/f"},
+                {"op": "test", "path": "/f/2", "value": 3.0}
+            ]"#,
+        );
+        document.apply_patch(&patch).unwrap();
+        assert_eq!(document, parse_json(r#"{"a": {"b": [0, 2, 3]}, "c": {}, "e": null, "f": [0, 2, 3]}"#));
+
+        // A failed operation leaves the document as it was
+        let before = document.clone();
+        let patch = parse_json(r#"[{"op": "remove", "path": "/e"}, {"op": "test", "path": "/c", "value": 1}]"#);
+        assert_eq!(document.apply_patch(&patch), Err("Test failed at /c".to_string()));
+        assert_eq!(document, before);
+
+        let failures = [
+            (r#"[{"op": "add", "path": "/a/b/9", "value": 1}]"#, "Array index out of bounds: /a/b/9"),
+            (r#"[{"op": "remove", "path": "/a/b/-"}]"#, "Invalid array index '-'"),
+            (r#"[{"op": "move", "from": "/a", "path": "/a/x"}]"#, "Cannot move a value into one of its children"),
+            (r#"[{"op": "copy", "from": "/zz", "path": "/y"}]"#, "Path not found: /zz"),
+            (r#"[{"op": "frobnicate", "path": ""}]"#, "Unknown patch operation 'frobnicate'"),
+            (r#"{"op": "add"}"#, "Patch must be an array of operations"),
+        ];
+        for (patch, message) in failures {
+            assert_eq!(document.apply_patch(&parse_json(patch)), Err(message.to_string()), "{}", patch);
+        }
+    }
+
+    #[test]
+    fn attribute_values_decode_entities() {
+        let root = parse_xml(r#"<a title="a &quot; b" q='it&apos;s "x"' m="&lt;&amp;&gt;&#65;&#x42;"/>"#);
+        assert_eq!(root.attribute("title"), Some("a \" b"));
+        assert_eq!(root.attribute("q"), Some("it's \"x\""));
+        assert_eq!(root.attribute("m"), Some("<&>AB"));
+
+        let root = parse_xml(r#"<!DOCTYPE a [<!ENTITY e "v">]><a x="[&e;]"/>"#);
+        assert_eq!(root.attribute("x"), Some("[v]"));
+
+        // A raw delimiter quote ends the value
+        let error = xml_error(r#"<a title="a " b"/>"#, XmlParserOptions::default());
+        assert_eq!(error.to_string(), "Invalid character '\"' in attribute name 'b' at line 1, column 16");
+        let error = xml_error(r#"<a title="&bogus;"/>"#, XmlParserOptions::default());
+        assert!(matches!(error, XmlError::UnknownEntity { .. }), "{:?}", error);
+    }
+
+    #[test]
+    fn overflowing_numbers_fail_and_negative_zero_can_be_normalized() {
+        for input in ["1e400", "-1e400", "[1, 1E+309]", &"9".repeat(400)] {
+            assert_eq!(JsonParser::new(input).parse().unwrap_err().to_string(), "Number out of range", "{}", input);
+        }
+        assert_eq!(parse_json("1e-400").as_f64(), Some(0.0));
+
+        let normalize = || JsonParserOptions { normalize_negative_zero: true, ..JsonParserOptions::default() };
+        for input in ["-0", "-0.0", "-0e5", "-1e-400"] {
+            let kept = parse_json(input).as_f64().unwrap();
+            assert!(kept == 0.0 && kept.is_sign_negative(), "{}", input);
+            let normalized = JsonParser::with_options(input, normalize()).parse().unwrap().as_f64().unwrap();
+            assert!(normalized == 0.0 && normalized.is_sign_positive(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn forced_array_paths_only_affect_matching_elements() {
+        let root = parse_xml(
+            "<catalog><book><authors><author>A</author></authors><title>T</title></book>\
+             <author>top</author></catalog>",
+        );
+        let options = ToJsonOptions {
+            force_array_paths: ["/catalog/book/authors/author".to_string()].into(),
+            ..ToJsonOptions::default()
+        };
+        assert_eq!(
+            root.to_json_string_with_options(&options),
+            r#"{"book":{"authors":{"author":["A"]},"title":"T"},"author":"top"}"#
+        );
+        assert_eq!(
+            root.to_json_string(),
+            r#"{"book":{"authors":{"author":"A"},"title":"T"},"author":"top"}"#
+        );
+    }
+
+    #[test]
+    fn every_truncated_json_document_is_unexpected_eof() {
+        let document = r#"{"a": [1, -2.5e+3, true, false, null], "b\u00e9\n": {"c": "d"}}"#;
+        for end in 0..document.len() {
+            let error = JsonParser::new(&document[..end]).parse().unwrap_err();
+            assert!(matches!(error, ParseError::UnexpectedEof { .. }), "{:?}: {}", &document[..end], error);
+        }
+    }
+
+    #[test]
+    fn delimiter_errors_give_location_and_found_character() {
+        let errors = [
+            ("[1, 2\n  3]", "line 2, column 3: expected ',' or ']', found '3'"),
+            ("{\n  \"a\": 1,\n  \"b\": [1,\n    2}\n}", "line 4, column 6: expected ',' or ']', found '}'"),
+            ("{\"a\": 1 \"b\": 2}", "line 1, column 9: expected ',' or '}', found '\"'"),
+            ("{\"a\" 1}", "line 1, column 6: expected ':', found '1'"),
+            ("{\"a\"]", "line 1, column 5: expected ':', found ']'"),
+        ];
+        for (input, message) in errors {
+            assert_eq!(JsonParser::new(input).parse().unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn parse_array_lenient_skips_bad_elements() {
+        let input = r#"[1, {"a": [2,, 3]}, "ok", [3,, "]"], 4 5, {"b": [6]}, tru]"#;
+        let (values, errors) = JsonParser::new(input).parse_array_lenient().unwrap();
+        assert_eq!(values, ["1", "\"ok\"", "{\"b\": [6]}"].map(parse_json));
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+
+        let (values, errors) = JsonParser::new("[]").parse_array_lenient().unwrap();
+        assert!(values.is_empty() && errors.is_empty());
+        assert!(JsonParser::new("{}").parse_array_lenient().is_err());
+        assert!(matches!(
+            JsonParser::new("[1, 2").parse_array_lenient(),
+            Err(ParseError::UnexpectedEof { .. })
+        ));
+    }
+
+    #[test]
+    fn object_keys_must_be_strings() {
+        for (input, found, position) in [("{42: \"x\"}", '4', 1), ("{\"a\": 1, b: 2}", 'b', 9), ("{ null: 1}", 'n', 2)] {
+            let mut parser = JsonParser::new(input);
+            let error = parser.parse().unwrap_err();
+            assert_eq!(error.to_string(), format!("Object key must be a string, found '{}'", found));
+            assert_eq!(parser.position(), position);
+        }
+    }
+
+    #[test]
+    fn declaration_is_parsed_after_a_bom_and_whitespace() {
+        let input = "\u{feff} \n<?xml version=\"1.0\" encoding='UTF-8' standalone=\"yes\" ?><?pi data?>\n<a/>";
+        let mut parser = XmlParser::new(input);
+        assert_eq!(parser.parse().unwrap().tag(), "a");
+        let declaration = parser.declaration().unwrap();
+        assert_eq!(declaration.version, "1.0");
+        assert_eq!(parser.declared_encoding(), Some("UTF-8"));
+        assert_eq!(declaration.standalone, Some(true));
+
+        let mut parser = XmlParser::new("<?xml-stylesheet href='s.xsl'?><a/>");
+        assert_eq!(parser.parse().unwrap().tag(), "a");
+        assert!(parser.declaration().is_none());
+
+        let errors = [
+            ("<?xml encoding='UTF-8'?><a/>", "Unexpected 'encoding' in XML declaration"),
+            ("<?xml version='1.0' standalone='maybe'?><a/>", "Invalid standalone value 'maybe'"),
+            ("<?xml ?><a/>", "XML declaration is missing version"),
+        ];
+        for (input, message) in errors {
+            assert!(xml_error(input, XmlParserOptions::default()).to_string().starts_with(message), "{}", input);
+        }
+
+        let options = XmlParserOptions { lowercase_names: true, ..XmlParserOptions::default() };
+        let error = xml_error("<?xml VERSION='1.0'?><a/>", options);
+        assert!(error.to_string().starts_with("Unexpected 'VERSION' in XML declaration"), "{}", error);
+    }
+
+    #[test]
+    fn text_decodes_predefined_entities() {
+        let root = parse_xml("<a>&amp;&lt;&gt;&quot;&apos; &amp;amp;</a>");
+        assert_eq!(root.text(), Some("&<>\"' &amp;"));
+        assert_eq!(parse_xml(&root.to_xml_string()).text(), root.text());
+
+        let error = xml_error("<a>x &foo; y</a>", XmlParserOptions::default());
+        assert!(matches!(error, XmlError::UnknownEntity { ref name, .. } if name == "foo"), "{:?}", error);
+        assert!(error.to_string().starts_with("Unknown entity reference"), "{}", error);
+    }
+
+    #[test]
+    fn character_references_decode_in_text_and_attributes() {
+        let root = parse_xml("<a v='&#x41;&#66;&#x1F600;'>&#65;&#x42;&#xe9;&#233;</a>");
+        assert_eq!(root.text(), Some("AB\u{e9}\u{e9}"));
+        assert_eq!(root.attribute("v"), Some("AB\u{1f600}"));
+
+        for reference in ["&#x110000;", "&#1114112;", "&#xG1;", "&#12a;", "&#;", "&#x;", "&#xD800;"] {
+            let error = xml_error(&format!("<a>{}</a>", reference), XmlParserOptions::default());
+            let message = format!("Invalid character reference '{}'", reference);
+            assert!(error.to_string().starts_with(&message), "{}: {}", reference, error);
+        }
+    }
+
+    #[test]
+    fn attribute_values_take_either_quote() {
+        let root = parse_xml(r#"<a href='x' title="it's" q='say "hi"'/>"#);
+        assert_eq!(root.attribute("href"), Some("x"));
+        assert_eq!(root.attribute("title"), Some("it's"));
+        assert_eq!(root.attribute("q"), Some("say \"hi\""));
+
+        for input in [r#"<a href='x"/>"#, r#"<a href="x'/>"#] {
+            let error = xml_error(input, XmlParserOptions::default());
+            assert!(matches!(error, XmlError::UnexpectedEof { .. }), "{}: {:?}", input, error);
+        }
+        let error = xml_error("<a href=x/>", XmlParserOptions::default());
+        assert_eq!(error.to_string(), "Expected quoted value for attribute 'href', found 'x' at line 1, column 9");
+    }
+
+    #[test]
+    fn xml_errors_carry_line_and_column() {
+        let input = "<root>\n  <caf\u{e9}>\n    text\n  </cafe>\n</root>";
+        let error = xml_error(input, XmlParserOptions::default());
+        assert_eq!(error.position(), Some(Position { line: 4, column: 3, offset: 27 }));
+        assert!(error.to_string().ends_with(" at line 4, column 3"), "{}", error);
+
+        let mut parser = XmlParser::new("<a>\n\n<b x=1/></a>");
+        let error = parser.parse().unwrap_err();
+        let position = error.position().unwrap();
+        assert_eq!((position.line, position.column), (3, 6));
+
+        let mut parser = XmlParser::new("<a>\n  <b/>\n</a>  \n");
+        parser.parse().unwrap();
+        assert_eq!(parser.location(), Position { line: 4, column: 1, offset: 18 });
+    }
+
+    #[test]
+    fn to_xml_string_escapes_and_reparses() {
+        let mut node = XmlNode::new("a".to_string());
+        node.set_attribute("q", "\"<&>'");
+        node.set_attribute("b", "1");
+        node.set_text(Some("x < y & z > w".to_string()));
+        node.add_child(XmlNode::new("empty".to_string()));
+        assert_eq!(
+            node.to_xml_string(),
+            "<a b=\"1\" q=\"&quot;&lt;&amp;>'\">x &lt; y &amp; z > w<empty/></a>"
+        );
+
+        let mut node = XmlNode::new("a".to_string());
+        node.set_attribute("x", "p\tq\nr\r\ns");
+        assert_eq!(node.to_xml_string(), "<a x=\"p&#x9;q&#xA;r&#xD;&#xA;s\"/>");
+        assert_eq!(parse_xml(&node.to_xml_string()), node);
+
+        let documents = [
+            "<a/>",
+            "<r x='1' y=\"&apos;&quot;\"><b>t</b><b/><c><d e='&amp;'>&lt;</d></c></r>",
+            "<r>mixed<b/>text &amp; <![CDATA[<cdata>]]></r>",
+        ];
+        for input in documents {
+            let root = parse_xml(input);
+            assert_eq!(parse_xml(&root.to_xml_string()), root, "{}", input);
+        }
+    }
+
+    #[test]
+    fn to_xml_pretty_indents_nested_elements() {
+        let root = parse_xml("<r z='1' a='2'><name>value</name><list><item/><item>x</item></list></r>");
+        let expected = "<r a=\"2\" z=\"1\">\n  <name>value</name>\n  <list>\n    <item/>\n    <item>x</item>\n  </list>\n</r>\n";
+        assert_eq!(root.to_xml_pretty(2), expected);
+        assert_eq!(root.to_xml_pretty(0), "<r a=\"2\" z=\"1\">\n<name>value</name>\n<list>\n<item/>\n<item>x</item>\n</list>\n</r>\n");
+
+        // Attributes come out in name order whatever order they were set in
+        let mut node = XmlNode::new("a".to_string());
+        for name in ["m", "b", "z", "a", "k"] {
+            node.set_attribute(name, name);
+        }
+        assert_eq!(node.to_xml_pretty(2), "<a a=\"a\" b=\"b\" k=\"k\" m=\"m\" z=\"z\"/>\n");
+
+        let options = XmlParserOptions { trim_text: true, ..XmlParserOptions::default() };
+        let mixed = parse_xml("<r>text<b>c</b></r>");
+        let pretty = mixed.to_xml_pretty(2);
+        assert_eq!(pretty, "<r>\n  text\n  <b>c</b>\n</r>\n");
+        assert_eq!(XmlParser::with_options(&pretty, options).parse().unwrap(), mixed);
+    }
+
+    // Deterministic xorshift generator for the round-trip tests, so a failure
+    // reproduces from its seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+            choices[self.below(choices.len())]
+        }
+
+        fn string(&mut self, pieces: &[&str]) -> String {
+            (0..self.below(5)).map(|_| self.pick(pieces)).collect()
+        }
+    }
+
+    const STRING_PIECES: &[&str] = &[
+        "a", "Z9", " ", "\"", "\\", "/", "\n", "\t", "\u{1}", "\u{e9}", "\u{1f600}", "<&>", "]]>",
+    ];
+
+    fn random_json(rng: &mut Rng, depth: usize) -> JsonValue {
+        match rng.below(if depth == 0 { 5 } else { 7 }) {
+            0 => JsonValue::Null,
+            1 => JsonValue::Boolean(rng.below(2) == 0),
+            2 => JsonValue::Integer(rng.next() as i64 >> rng.below(64)),
+            3 => {
+                let value = f64::from_bits(rng.next());
+                JsonValue::Number(if value.is_finite() { value } else { 0.5 })
+            }
+            4 => JsonValue::String(rng.string(STRING_PIECES)),
+            5 => JsonValue::Array((0..rng.below(4)).map(|_| random_json(rng, depth - 1)).collect()),
+            _ => JsonValue::Object(
+                (0..rng.below(4)).map(|_| (rng.string(STRING_PIECES), random_json(rng, depth - 1))).collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn json_round_trips_through_both_layouts() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let value = random_json(&mut rng, 4);
+            for json in [value.to_string(), format!("{:#}", value)] {
+                assert_eq!(JsonParser::new(&json).parse().unwrap(), value, "{}", json);
+            }
+        }
+    }
+
+    // Only trees the parser can produce: names from the XML name productions
+    // and non-blank text with no characters XML can't carry
+    fn random_xml(rng: &mut Rng, depth: usize) -> XmlNode {
+        const NAMES: &[&str] = &["a", "b", "item", "x-y", "ns:el", "_u", "caf\u{e9}", "n.1"];
+        let mut node = XmlNode::new(rng.pick(NAMES).to_string());
+        for _ in 0..rng.below(3) {
+            let value = rng.string(&["v", " ", "\t", "\n", "\r\n", "\"", "'", "<&>", "\u{1f600}", "]]>"]);
+            node.set_attribute(rng.pick(NAMES), &value);
+        }
+        if rng.below(2) == 0 {
+            let text = rng.string(STRING_PIECES).replace('\u{1}', "");
+            node.set_text(Some(format!("t{}", text)));
+        }
+        if depth > 0 {
+            for _ in 0..rng.below(4) {
+                node.add_child(random_xml(rng, depth - 1));
+            }
+        }
+        node
+    }
+
+    #[test]
+    fn xml_round_trips_through_to_xml_string() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..2000 {
+            let node = random_xml(&mut rng, 3);
+            let explicit = WriteXmlOptions {
+                empty_element_style: EmptyElementStyle::ExplicitClose,
+                ..WriteXmlOptions::default()
+            };
+            for xml in [node.to_xml_string(), node.to_xml_string_with_options(&explicit)] {
+                let options = XmlParserOptions { trim_text: false, ..XmlParserOptions::default() };
+                assert_eq!(XmlParser::with_options(&xml, options).parse().unwrap(), node, "{}", xml);
+            }
+        }
+    }
+
+    #[test]
+    fn reset_parsers_start_over_on_new_input() {
+        let mut parser = JsonParser::with_options("[1, +2]", lenient_json());
+        assert_eq!(parser.parse().unwrap(), parse_json("[1, 2]"));
+        parser.reset("{\"a\": ");
+        assert!(parser.parse().is_err());
+        parser.reset("{\"a\": .5}");
+        assert_eq!(parser.parse().unwrap(), parse_json("{\"a\": 0.5}"));
+        assert_eq!(parser.position(), 9);
+
+        let mut parser = XmlParser::new("<?xml version='1.0'?><!DOCTYPE a [<!ENTITY e 'v'>]><a>&e;</a>");
+        assert_eq!(parser.parse().unwrap().text(), Some("v"));
+        parser.reset("<a>&e;</a>");
+        assert!(matches!(parser.parse(), Err(XmlError::UnknownEntity { .. })));
+        parser.reset("<b/>");
+        assert_eq!(parser.parse().unwrap().tag(), "b");
+        assert!(parser.declaration().is_none());
+
+        let mut parser = JsonParser::from_chars("[true]".chars().collect());
+        assert_eq!(parser.parse().unwrap(), parse_json("[true]"));
+    }
+
+    #[test]
+    fn entity_names_keep_their_case_under_lowercase_names() {
+        let options = XmlParserOptions { lowercase_names: true, ..XmlParserOptions::default() };
+        let input = "<!DOCTYPE A [<!ENTITY Co \"Acme\">]><A B='&Co;'>&Co;</A>";
+        let root = XmlParser::with_options(input, options.clone()).parse().unwrap();
+        assert_eq!((root.tag(), root.attribute("b"), root.text()), ("a", Some("Acme"), Some("Acme")));
+
+        let error = xml_error("<!DOCTYPE a [<!ENTITY Co \"Acme\">]><a>&co;</a>", options);
+        assert!(matches!(error, XmlError::UnknownEntity { ref name, .. } if name == "co"), "{:?}", error);
+    }
+
+    #[test]
+    fn entity_values_cannot_refer_to_other_entities() {
+        let input = "<!DOCTYPE a [\n<!ENTITY a \"x\">\n<!ENTITY b \"&lt;&a;!\">\n]><a>&b;</a>";
+        let error = xml_error(input, XmlParserOptions::default());
+        assert_eq!(error.to_string(), "Entity 'b' refers to entity 'a', which is not supported at line 3, column 17");
+
+        let error = xml_error("<!DOCTYPE a [<!ENTITY b '&b;'>]><a>&b;</a>", XmlParserOptions::default());
+        assert!(matches!(error, XmlError::Syntax { ref message, .. } if message.contains("entity 'b'")), "{:?}", error);
+
+        let root = parse_xml("<!DOCTYPE a [<!ENTITY b '&lt;&#65;&amp;'>]><a>&b;</a>");
+        assert_eq!(root.text(), Some("<A&"));
+    }
+
+    #[test]
+    fn type_hints_round_trip_every_json_type() {
+        let value = parse_json(
+            r#"{"n": null, "t": true, "f": false, "i": -7, "x": 2.5, "s": "text", "p": " padded ",
+                "w": " \n\t", "q": " a]]>b ", "e": "", "z": "0", "a": [1, "two", [], {}],
+                "o": {"k": {"deep": " v"}}, "ea": [], "eo": {}}"#,
+        );
+        let to_xml = ToXmlOptions { type_hints: true, ..ToXmlOptions::default() };
+        let to_json = ToJsonOptions { type_hints: true, ..ToJsonOptions::default() };
+        let xml = value.to_xml_with_options(&to_xml);
+        assert_eq!(parse_xml(&xml).to_json_value_with_options(&to_json), value, "{}", xml);
+    }
+
+    #[test]
+    fn trailing_newline_ends_xml_output() {
+        let root = parse_xml("<r><a/></r>");
+        assert_eq!(root.to_xml_string(), "<r><a/></r>");
+        assert_eq!(root.to_xml_pretty(2), "<r>\n  <a/>\n</r>\n");
+        assert_eq!(reformat_xml("<r><a/></r>", 2).unwrap(), "<r>\n  <a/>\n</r>\n");
+
+        let with_newline = WriteXmlOptions { trailing_newline: true, ..WriteXmlOptions::default() };
+        assert_eq!(root.to_xml_string_with_options(&with_newline), "<r><a/></r>\n");
+        let without = WriteXmlOptions::default();
+        assert_eq!(root.to_xml_pretty_with_options(2, &without), "<r>\n  <a/>\n</r>");
+    }
+
+    #[test]
+    fn namespace_declarations_round_trip_without_repeating() {
+        let input = concat!(
+            r#"<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope">"#,
+            r#"<soap:Body><m:Get xmlns:m="urn:example:m"/></soap:Body></soap:Envelope>"#,
+        );
+        let root = parse_xml(input);
+        let xml = root.to_xml_string();
+        assert_eq!(xml, input);
+        assert_eq!(parse_xml(&xml), root);
+        assert_eq!(xml.matches("xmlns:soap=").count(), 1);
+        assert_eq!(xml.matches("xmlns:m=").count(), 1);
+
+        let body = &root.children()[0];
+        assert_eq!(body.attributes().len(), 0);
+        assert_eq!(body.children()[0].attribute("xmlns:m"), Some("urn:example:m"));
+    }
+}